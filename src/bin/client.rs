@@ -2,15 +2,19 @@ use std::{io::Write, num::NonZeroU32};
 
 use glutin::surface::{GlSurface, Surface, WindowSurface};
 use infinirust::{
-    game::{Game, Key},
+    game::{CommandLineInput, Game, Key},
     misc::{login, start_server},
     mygl::GLToken,
+    net::{
+        Handshake, RUN_LENGTH_CHUNKS,
+        secure::{Transport, from_hex},
+    },
 };
 use winit::{
     application::ApplicationHandler,
     event::{ElementState, MouseButton, WindowEvent},
     event_loop::ControlFlow,
-    keyboard::KeyCode,
+    keyboard::{KeyCode, NamedKey},
 };
 
 struct App {
@@ -36,15 +40,13 @@ impl ApplicationHandler for App {
             WindowEvent::CloseRequested => {
                 event_loop.exit();
             }
-            WindowEvent::Resized(size) => {
-                if size.width != 0 && size.height != 0 {
-                    self.surface.resize(
-                        &self.gl_context,
-                        NonZeroU32::new(size.width).unwrap(),
-                        NonZeroU32::new(size.height).unwrap(),
-                    );
-                    self.game.resize(self.glt, size);
-                }
+            WindowEvent::Resized(size) if size.width != 0 && size.height != 0 => {
+                self.surface.resize(
+                    &self.gl_context,
+                    NonZeroU32::new(size.width).unwrap(),
+                    NonZeroU32::new(size.height).unwrap(),
+                );
+                self.game.resize(self.glt, size);
             }
             WindowEvent::KeyboardInput {
                 device_id: _,
@@ -55,7 +57,30 @@ impl ApplicationHandler for App {
                     ElementState::Pressed => true,
                     ElementState::Released => false,
                 };
-                if let winit::keyboard::PhysicalKey::Code(code) = event.physical_key {
+                if self.game.command_line_open() {
+                    // The command line takes all keys until it is closed
+                    if pressed {
+                        let input = match &event.logical_key {
+                            winit::keyboard::Key::Named(NamedKey::Escape) => {
+                                Some(CommandLineInput::Close)
+                            }
+                            winit::keyboard::Key::Named(NamedKey::Enter) => {
+                                Some(CommandLineInput::Submit)
+                            }
+                            winit::keyboard::Key::Named(NamedKey::Backspace) => {
+                                Some(CommandLineInput::Backspace)
+                            }
+                            _ => None,
+                        };
+                        if let Some(input) = input {
+                            self.game.command_line_input(input);
+                        } else if let Some(text) = &event.text {
+                            for c in text.chars() {
+                                self.game.command_line_input(CommandLineInput::Char(c));
+                            }
+                        }
+                    }
+                } else if let winit::keyboard::PhysicalKey::Code(code) = event.physical_key {
                     match code {
                         KeyCode::KeyA => {
                             self.game.keyboard_input(Key::Left, pressed);
@@ -97,6 +122,9 @@ impl ApplicationHandler for App {
                             self.window.set_cursor_visible(true);
                             self.window.set_fullscreen(None)
                         }
+                        KeyCode::KeyT if pressed => {
+                            self.game.open_command_line();
+                        }
                         KeyCode::F3 => {
                             self.game.keyboard_input(Key::DebugScreen, pressed);
                         }
@@ -155,26 +183,67 @@ impl ApplicationHandler for App {
         _device_id: winit::event::DeviceId,
         event: winit::event::DeviceEvent,
     ) {
-        match event {
-            winit::event::DeviceEvent::MouseMotion { delta } => {
-                self.game.mouse_input((delta.0, delta.1));
-            }
-            _ => {}
+        if let winit::event::DeviceEvent::MouseMotion { delta } = event {
+            self.game.mouse_input((delta.0, delta.1));
         }
     }
 
     fn exiting(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {}
 }
 
+/// The password, the transport and the handshake from the arguments after the name.
+/// Worlds played alone do not need a password, the empty one is used then.
+fn parse_options(args: &[String]) -> Option<(String, Transport, Handshake)> {
+    let mut password = String::new();
+    let mut transport = Transport::Plain;
+    let mut handshake = Handshake::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--encrypt" => {
+                if transport == Transport::Plain {
+                    transport = Transport::Encrypted { pin: None };
+                }
+            }
+            "--pin" => {
+                let pin = from_hex(args.next()?).ok()?;
+                transport = Transport::Encrypted { pin: Some(pin) };
+            }
+            // Asks the server for uncompressed chunks
+            "--raw-chunks" => handshake = handshake.without(RUN_LENGTH_CHUNKS),
+            _ if password.is_empty() && !arg.starts_with("--") => password = arg.clone(),
+            _ => return None,
+        }
+    }
+    Some((password, transport, handshake))
+}
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    let (password, transport, handshake) = parse_options(&args[3..]).unwrap_or_else(|| {
+        eprintln!(
+            "Usage: client <world_dir|ip:port> <name> [password] [--encrypt] [--pin <server key>] [--raw-chunks]"
+        );
+        std::process::exit(1);
+    });
 
-    let ((server_tcp, uid), mut server_process) = if !args[1].contains(':') {
+    let (login, mut server_process) = if !args[1].contains(':') {
+        // The internal server only listens on loopback and does not encrypt
         let (server_process, bind) = start_server(&args[1]);
-        (login(&bind, &args[2]), Some(server_process))
+        (
+            login(&bind, &args[2], &password, &Transport::Plain, handshake),
+            Some(server_process),
+        )
     } else {
-        (login(&args[1], &args[2]), None)
+        (
+            login(&args[1], &args[2], &password, &transport, handshake),
+            None,
+        )
     };
+    let (server_tcp, uid, _) = login.unwrap_or_else(|e| {
+        eprintln!("Client: {e}");
+        std::process::exit(1);
+    });
 
     let (event_loop, window, surface, gl_context) = infinirust::window::create_window();
 
@@ -1,20 +1,61 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, ensure};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{
-    TcpListener,
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
-use zerocopy::IntoBytes;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
 
-use infinirust::net::{ClientPackagePlayerPosition, Package, PackageBlockUpdate};
+use infinirust::game::CHUNK_SIZE;
+use infinirust::net::secure::{self, Reader, ServerKey, Writer};
+use infinirust::net::{
+    ClientBound, Handshake, PROTOCOL_VERSION, Package, ServerBound, truncate_reason,
+};
+use infinirust::server::auth::{self, Account, Credential, LoginAttempts};
+use infinirust::server::player::{LoginRequest, is_valid_name};
 use infinirust::server::{Client, Command, NOUSER, ServerCommand, UID};
 
+/// Chunks further away from the origin have block coordinates which do not fit into an i32
+const MAX_CHUNK_COORDINATE: i32 = i32::MAX / CHUNK_SIZE as i32 - 1;
+
+/// Connections which are not logged in after this time are closed
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long the reason of a kick waits for room in the write channel of a client which does
+/// not read its packages
+const KICK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What the logins of all connections share
+struct LoginPolicy {
+    attempts: LoginAttempts,
+    /// Only the internal server of a local game lets the login set the password of
+    /// accounts from before passwords existed, see [`Account::NoPassword`]
+    claim_legacy: bool,
+}
+
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
     let listen_on = args[1].clone();
     let world_directory = args[2].clone();
+    // With `--key <path>` all connections are encrypted, the key file is created if it is missing
+    let key = match args.get(3..) {
+        Some([option, path]) if option == "--key" => {
+            let key = ServerKey::load_or_create(path.as_ref()).unwrap_or_else(|e| {
+                eprintln!("Server: {e:#}");
+                std::process::exit(1);
+            });
+            eprintln!("Server: Public key {}", secure::to_hex(&key.public));
+            Some(Arc::new(key))
+        }
+        Some([]) | None => None,
+        Some(_) => {
+            eprintln!("Usage: server <ip:port|internal> <world_dir> [--key <path>]");
+            std::process::exit(1);
+        }
+    };
+
+    let policy = Arc::new(LoginPolicy {
+        attempts: LoginAttempts::default(),
+        claim_legacy: listen_on == "internal",
+    });
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -34,7 +75,10 @@ fn main() -> std::io::Result<()> {
 
         // Channel to send commands to the server world (shared state of the server)
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(10000);
-        std::thread::spawn(|| infinirust::server::start_world(command_rx, world_directory.into()));
+        let world_command_tx = command_tx.clone();
+        std::thread::spawn(|| {
+            infinirust::server::start_world(command_rx, world_command_tx, world_directory.into())
+        });
 
         // Spwan the stdin thread and give it access to send server world commands
         let stdin_command_tx = command_tx.clone();
@@ -54,28 +98,70 @@ fn main() -> std::io::Result<()> {
 
         // accept connections and process them in a new task
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
-            let (read, write) = stream.into_split();
-
-            let (write_tx, write_rx) = tokio::sync::mpsc::channel(10000);
-
-            tokio::task::spawn(write_packages(write, write_rx));
-            tokio::task::spawn(read_start_packages(read, command_tx.clone(), write_tx));
+            let (stream, address) = match listener.accept().await {
+                Ok((stream, address)) => (stream, address.ip()),
+                Err(e) => {
+                    eprintln!("Server: Could not accept connection: {e}");
+                    continue;
+                }
+            };
+            tokio::task::spawn(start_connection(
+                stream,
+                address,
+                key.clone(),
+                policy.clone(),
+                command_tx.clone(),
+            ));
         }
     });
 
     std::io::Result::Ok(())
 }
 
-async fn write_packages(
-    mut stream: OwnedWriteHalf,
-    mut input: tokio::sync::mpsc::Receiver<Arc<[u8]>>,
+/// Does the encryption handshake if the server has a key and starts reading and writing packages
+async fn start_connection(
+    stream: TcpStream,
+    address: IpAddr,
+    key: Option<Arc<ServerKey>>,
+    policy: Arc<LoginPolicy>,
+    server: ServerCommand,
 ) {
+    let (read, write) = match key {
+        // A client which never sends the handshake is dropped after the login timeout
+        Some(key) => {
+            match tokio::time::timeout(LOGIN_TIMEOUT, secure::accept(stream, &key)).await {
+                Ok(Ok(halves)) => halves,
+                Ok(Err(e)) => {
+                    eprintln!("Server: Encryption handshake failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("Server: Encryption handshake timed out");
+                    return;
+                }
+            }
+        }
+        None => {
+            let (read, write) = stream.into_split();
+            (Box::new(read) as Reader, Box::new(write) as Writer)
+        }
+    };
+
+    let (write_tx, write_rx) = tokio::sync::mpsc::channel(10000);
+
+    tokio::task::spawn(write_packages(write, write_rx));
+    tokio::task::spawn(read_start_packages(read, server, write_tx, address, policy));
+}
+
+async fn write_packages(mut stream: Writer, mut input: tokio::sync::mpsc::Receiver<Arc<[u8]>>) {
     //TODO Consider accepting a package enum instead of the written out packages
     loop {
-        //This unwraps panics iff the user is logged out
         if let Some(package) = input.recv().await {
-            stream.write_all(&package).await.unwrap();
+            // The reader notices the closed connection and logs the player out
+            if let Err(e) = stream.write_all(&package).await {
+                eprintln!("Server Writer returns because of error: {e}");
+                return;
+            }
         } else {
             eprintln!("Server Writer returns");
             return;
@@ -87,108 +173,219 @@ async fn write_packages(
 /// It will never return Ok. TODO never type if it gets stable
 /// If it returns Err the user will be logged out
 async fn read_play_packages(
-    mut stream: OwnedReadHalf,
+    mut stream: Reader,
     server: ServerCommand,
     uid: UID,
 ) -> Result<(), anyhow::Error> {
     loop {
-        let mut package_type = 0u16;
-        stream.read_exact(package_type.as_mut_bytes()).await?;
-
-        match package_type {
-            // Request chunk data
-            0x000A => {
-                let mut pos = [0i32; 3];
-                stream.read_exact(pos.as_mut_bytes()).await?;
-                let command = (uid, Command::ChunkData(pos));
-                server
-                    .send(command)
-                    .await
-                    .expect("This should never happen. The internal server is not responding");
+        let command = match ServerBound::read(&mut stream).await? {
+            ServerBound::RequestChunk { pos } => {
+                ensure!(
+                    pos.iter().all(|x| x.abs() <= MAX_CHUNK_COORDINATE),
+                    "Requested chunk {pos:?} is outside of the world"
+                );
+                Command::ChunkData(pos)
             }
-            // Send block update
-            0x000B => {
-                let mut package = PackageBlockUpdate::default();
-                stream.read_exact(package.as_mut_bytes()).await?;
-
-                server
-                    .send((uid, Command::BlockUpdate(package.pos, package.block)))
-                    .await
-                    .expect("This should never happen. The internal server is not responding");
+            ServerBound::BlockUpdate { pos, block } => Command::BlockUpdate(pos, block),
+            ServerBound::Command { line } => Command::Run(line),
+            ServerBound::PlayerPosition { pos, pitch, yaw } => {
+                Command::PlayerPosition(pos, pitch, yaw)
             }
-            // Player position
-            0x000C => {
-                ClientPackagePlayerPosition::read_and_handle(&mut stream, &server, uid).await;
+            package => {
+                return Err(anyhow!("Invalid package for state `play`: {package:?}"));
             }
-            _ => {
-                return Err(anyhow!("Invalid package type"));
-            }
-        }
+        };
+        server
+            .send((uid, command))
+            .await
+            .expect("This should never happen. The internal server is not responding");
     }
 }
 
 /// Read the packages when the server is in `start` state
-async fn read_start_packages(mut stream: OwnedReadHalf, server: ServerCommand, client: Client) {
-    let uid = loop {
-        let mut package_type = 0u16;
-        stream
-            .read_exact(package_type.as_mut_bytes())
-            .await
-            .unwrap();
-        match package_type {
-            // Login
-            0x0001 => {
-                let (tx, rx) = tokio::sync::oneshot::channel();
-
-                if let Some(username) = read_alpha_numeric_string(&mut stream).await {
-                    let command = Command::Login(username, client.clone(), tx);
-                    server.send((NOUSER, command)).await.unwrap();
-
-                    if let Some(uid) = rx.await.unwrap() {
-                        break uid; //Move on to play state
-                    }
-                }
-                //Login unsuccessful
+async fn read_start_packages(
+    mut stream: Reader,
+    server: ServerCommand,
+    client: Client,
+    address: IpAddr,
+    policy: Arc<LoginPolicy>,
+) {
+    let login = read_login(&mut stream, &server, &client, address, &policy);
+    let (uid, kicked) = match tokio::time::timeout(LOGIN_TIMEOUT, login).await {
+        Ok(Some(login)) => login,
+        Ok(None) => return,
+        Err(_) => {
+            reject_login(&client, "The login timed out".to_string()).await;
+            return;
+        }
+    };
 
-                //Send Login failed package with empty message. todo
-                client
-                    .send((b"\x01\x00\x00\x00" as &[u8]).into())
-                    .await
-                    .unwrap();
-                //Do not revieve anymore packages
-                return;
-            }
-            _ => {
-                eprintln!("Server: Recieved invalid package for state `start`");
+    //Go to play state, until the connection fails or the player is kicked
+    let e = tokio::select! {
+        result = read_play_packages(stream, server.clone(), uid) => {
+            result.expect_err("Somehow the read_play_packages function returned with Ok")
+        }
+        Ok(reason) = kicked => {
+            // The writer sends it after the queued packages and closes the connection
+            // once the player is logged out
+            let package = ClientBound::Disconnect {
+                reason: reason.clone(),
+            };
+            if tokio::time::timeout(KICK_TIMEOUT, client.send(package.to_arc()))
+                .await
+                .is_err()
+            {
+                eprintln!("Server: Could not send the kick reason, the client does not read");
             }
+            anyhow!("Kicked: {reason}")
         }
     };
 
-    //Go to play state
-    let e = read_play_packages(stream, server.clone(), uid)
-        .await
-        .expect_err("Somehow the read_play_packages function returned with Ok");
-
     //Log the player out
     eprintln!("Player got logged out because of error: {e}");
     server.send((uid, Command::Logout)).await.unwrap();
 }
 
-async fn read_alpha_numeric_string(stream: &mut OwnedReadHalf) -> Option<String> {
-    let mut length = [0u8; 2];
-    stream.read_exact(&mut length).await.unwrap();
+/// Reads the handshake and the login, None if the login failed and the connection is closed
+async fn read_login(
+    stream: &mut Reader,
+    server: &ServerCommand,
+    client: &Client,
+    address: IpAddr,
+    policy: &LoginPolicy,
+) -> Option<(UID, tokio::sync::oneshot::Receiver<String>)> {
+    // The answer sent to the client, the login is only accepted after the handshake
+    let mut handshake: Option<Handshake> = None;
+    loop {
+        let package = match ServerBound::read(stream).await {
+            Ok(package) => package,
+            Err(e) => {
+                eprintln!("Server: Could not read package in state `start`: {e}");
+                let reason = format!("Invalid package: {e}");
+                reject_login(client, reason).await;
+                return None;
+            }
+        };
+        match package {
+            ServerBound::Handshake(client_handshake) => {
+                if client_handshake.version != PROTOCOL_VERSION {
+                    let reason = format!(
+                        "Incompatible version: the server uses protocol version {PROTOCOL_VERSION}, the client version {}",
+                        client_handshake.version
+                    );
+                    reject_login(client, reason).await;
+                    return None;
+                }
+                let answer = client_handshake.answer();
+                // The writer is only gone if the connection is closed, the next read fails then
+                _ = client
+                    .send(ClientBound::Handshake(answer.clone()).to_arc())
+                    .await;
+                handshake = Some(answer);
+            }
+            ServerBound::Login { name, password } => {
+                let Some(handshake) = &handshake else {
+                    // Clients from before the handshake start with the login
+                    let reason = format!(
+                        "Incompatible version: the server uses protocol version {PROTOCOL_VERSION}, the client did not send a handshake"
+                    );
+                    reject_login(client, reason).await;
+                    return None;
+                };
+                if !is_valid_name(&name) {
+                    reject_login(client, "Invalid name".to_string()).await;
+                    return None;
+                }
+                if !policy.attempts.try_attempt(address) {
+                    let reason = "Too many login attempts, try again later".to_string();
+                    reject_login(client, reason).await;
+                    return None;
+                }
+                let credential =
+                    match check_password(server, &name, password, policy.claim_legacy).await {
+                        Ok(credential) => credential,
+                        Err(reason) => {
+                            reject_login(client, reason).await;
+                            return None;
+                        }
+                    };
 
-    let length = u16::from_le_bytes(length);
+                let (disconnect, kicked) = tokio::sync::oneshot::channel();
+                let request = LoginRequest {
+                    name,
+                    credential,
+                    chunk_encoding: handshake.chunk_encoding(),
+                    client: client.clone(),
+                    disconnect,
+                    address,
+                };
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                server
+                    .send((NOUSER, Command::Login(request, tx)))
+                    .await
+                    .unwrap();
 
-    let mut string = vec![0u8; length as usize];
+                match rx.await.unwrap() {
+                    Ok(uid) => return Some((uid, kicked)), //Move on to play state
+                    Err(reason) => {
+                        //Send Login failed package
+                        reject_login(client, reason).await;
+                        //Do not revieve anymore packages
+                        return None;
+                    }
+                }
+            }
+            package => {
+                eprintln!("Server: Recieved invalid package for state `start`: {package:?}");
+                let reason = "Expected a handshake or a login".to_string();
+                reject_login(client, reason).await;
+                return None;
+            }
+        }
+    }
+}
 
-    stream.read_exact(&mut string).await.unwrap();
+/// Checks the password against the account of the name.
+/// Hashing is slow, so it runs on the blocking threads instead of the world thread.
+/// Fails with the reason for the client.
+async fn check_password(
+    server: &ServerCommand,
+    name: &str,
+    password: String,
+    claim_legacy: bool,
+) -> Result<Credential, String> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    server
+        .send((NOUSER, Command::Account(name.to_string(), tx)))
+        .await
+        .unwrap();
+    let account = rx.await.unwrap();
 
-    let string = String::from_utf8(string).ok()?;
+    let hashing = tokio::task::spawn_blocking(move || match account {
+        Account::Free => Ok(Credential::Register(auth::hash_password(&password))),
+        Account::NoPassword if claim_legacy => {
+            Ok(Credential::Register(auth::hash_password(&password)))
+        }
+        Account::NoPassword => Err(
+            "The account has no password yet, ask the operator to set one with register"
+                .to_string(),
+        ),
+        Account::Password(hash) if auth::verify_password(&password, &hash) => {
+            Ok(Credential::Verified(hash))
+        }
+        Account::Password(_) => Err("Wrong password".to_string()),
+        Account::Closed => {
+            Err("Registration is closed, ask the operator to register the name".to_string())
+        }
+    });
+    hashing.await.expect("Password hashing panicked")
+}
 
-    if string.chars().all(char::is_alphanumeric) {
-        Some(string)
-    } else {
-        None
-    }
+/// Sends the login failure package, the connection is closed when the reader returns
+async fn reject_login(client: &Client, reason: String) {
+    let reason = truncate_reason(reason);
+    // The writer is already gone if the client closed the connection
+    _ = client
+        .send(ClientBound::LoginFailure { reason }.to_arc())
+        .await;
 }
@@ -1,46 +1,42 @@
-use std::{collections::HashSet, net::TcpStream, sync::Arc};
+use std::{collections::HashSet, sync::Arc};
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
-use zerocopy::IntoBytes;
+use tokio::io::AsyncWriteExt;
 
 use crate::{
     game::{
-        CHUNK_SIZE, Camera, Y_RANGE, blocks::BlocksConfig, chunk::block_position_to_chunk_index,
-        world::VIEW_DISTANCE,
+        Block, Camera,
+        blocks::BlocksConfig,
+        chunk::block_position_to_chunk_index,
+        palette::PalettedBlocks,
+        world::{in_view, view_offsets},
     },
     net::{
-        ClientPackagePlayerPosition, Package as NetworkPackage, PackageBlockUpdate,
-        ServerPackageLogout, ServerPackagePlayerPosition, ServerPlayerLogin,
+        ClientBound, Package, ServerBound,
+        secure::{Connection, Reader, Writer},
     },
     server::UID,
 };
 
 use super::{FreeCamera, World};
 
+/// How many lines of the answers to admin commands are kept
+const COMMAND_ANSWER_LINES: usize = 10;
+
 /// Updates which are send from the main loop to the background thread
 #[derive(Debug)]
 pub enum Update {
     /// The camera position has changed
     Pos(FreeCamera),
     /// A block has been updated
-    Block([i32; 3], u8),
+    Block([i32; 3], Block),
+    /// A line of the admin commands
+    Command(String),
     /// Exit the game
     Exit,
 }
 
-enum Package {
-    Chunk([i32; 3], Vec<u8>),
-    PlayerPositionUpdate(ServerPackagePlayerPosition),
-    PlayerLogin(ServerPlayerLogin),
-    PlayerLogout(ServerPackageLogout),
-    BlockUpdate(PackageBlockUpdate),
-}
-
 pub fn background_thread(
-    tcp: TcpStream,
+    connection: Connection,
     world: Arc<World>,
     updates: tokio::sync::mpsc::Receiver<Update>,
     blocks_config: Arc<BlocksConfig>,
@@ -53,14 +49,16 @@ pub fn background_thread(
         .unwrap();
 
     rt.block_on(async {
-        tcp.set_nonblocking(true).unwrap();
-        let tcp = tokio::net::TcpStream::from_std(tcp).unwrap();
-        let (reader, writer) = tcp.into_split();
+        let (reader, writer) = connection.into_split().unwrap();
 
         let (loader_tx, loader_rx) = tokio::sync::mpsc::channel(10000);
         let (writer_tx, writer_rx) = tokio::sync::mpsc::channel(10000);
 
-        let read_join_handle = tokio::spawn(read_packages(reader, loader_tx));
+        let read_join_handle = tokio::spawn(async move {
+            if let Err(e) = read_packages(reader, loader_tx).await {
+                eprintln!("Client: Stopped reading from the server: {e}");
+            }
+        });
         let write_join_handle = tokio::spawn(write_packages(writer, writer_rx));
 
         let world_join_handler = tokio::spawn(manage_world(
@@ -83,25 +81,31 @@ pub fn background_thread(
 async fn manage_world(
     world: Arc<World>,
     blocks_config: Arc<BlocksConfig>,
-    mut in_packages: tokio::sync::mpsc::Receiver<Package>,
-    out_packages: tokio::sync::mpsc::Sender<Box<[u8]>>,
+    mut in_packages: tokio::sync::mpsc::Receiver<ClientBound>,
+    out_packages: tokio::sync::mpsc::Sender<ServerBound>,
     mut client: tokio::sync::mpsc::Receiver<Update>,
     uid: UID,
 ) {
-    let mut current_world_center = [i32::MIN; 2]; // x, z
+    // Chunk of the camera, None forces a reload of the chunks around the camera
+    let mut current_world_center: Option<[i32; 3]> = None;
+    let view_offsets = view_offsets();
 
     loop {
         tokio::select! {
             package = in_packages.recv() => {
                 match package {
                     // Chunkdata recieved
-                    Some(Package::Chunk(pos, data)) => {
+                    Some(ClientBound::Chunk { pos, blocks, .. }) => {
+                        // The camera moved away since the chunk was requested
+                        if current_world_center.is_some_and(|center| !in_view(pos, center)) {
+                            continue;
+                        }
                         // Both locks in this section are sync, but we do not await here
                         let mut unused_chunks_rx = world.unused_chunks.lock().unwrap();
                         let mut chunk = {
                             unused_chunks_rx.pop().expect("Client: No available chunks")
                         };
-                        chunk.load(data, pos);
+                        chunk.load(PalettedBlocks::from_blocks(&blocks), pos);
                         chunk.write_vbo(&blocks_config);
                         {
                             // This lock is time critical for the renderer thread, so be quick about it
@@ -112,75 +116,83 @@ async fn manage_world(
                             }
                         }
                     }
-                    Some(Package::PlayerPositionUpdate(package)) => {
+                    Some(ClientBound::PlayerPosition { uid: player, pos, pitch, yaw }) => {
                         // Update player position
-                        world.players.lock().unwrap().update(&package);
-                        if package.uid as UID == uid {
+                        world.players.lock().unwrap().update(player as UID, pos, pitch, yaw);
+                        if player as UID == uid {
                             // Force update
-                            current_world_center = [i32::MIN; 2];
+                            current_world_center = None;
                         }
                     }
-                    Some(Package::PlayerLogin(package)) => {
+                    Some(ClientBound::PlayerLogin { uid, name }) => {
                         // Add player to world
-                        world.players.lock().unwrap().add_player(package.name, package.uid as UID);
+                        world.players.lock().unwrap().add_player(name, uid as UID);
                     }
-                    Some(Package::PlayerLogout(package)) => {
+                    Some(ClientBound::PlayerLogout { uid }) => {
                         // Remove player from world
-                        world.players.lock().unwrap().remove_player(package.uid as UID);
+                        world.players.lock().unwrap().remove_player(uid as UID);
                     }
-                    Some(Package::BlockUpdate(package)) => {
-                        // Update block in chunk
-                        let (chunk_index, block_index) = block_position_to_chunk_index(package.pos);
+                    Some(ClientBound::BlockUpdate { pos, block } | ClientBound::BlockUpdateRejected { pos, block }) => {
+                        // Update block in chunk, a rejected update reverts the block
+                        let (chunk_index, block_index) = block_position_to_chunk_index(pos);
                         let mut chunks = world.chunks.lock().unwrap();
                             if let Some(chunk) = chunks.get_mut(&chunk_index) {
-                                chunk.update_block(block_index, package.block, &blocks_config);
+                                chunk.update_block(block_index, block, &blocks_config);
                             }
 
                     }
-                    None => {eprintln!("Client: Package reader stoped (probably lost connection to server), exiting"); return;},
+                    Some(ClientBound::CommandAnswer { answer }) => {
+                        let mut answers = world.command_answers.lock().unwrap();
+                        answers.extend(answer.lines().map(str::to_string));
+                        let old = answers.len().saturating_sub(COMMAND_ANSWER_LINES);
+                        answers.drain(..old);
+                    }
+                    Some(ClientBound::Disconnect { reason }) => {
+                        eprintln!("Client: Disconnected by the server: {reason}");
+                        *world.disconnect_reason.lock().unwrap() = Some(reason);
+                        return;
+                    }
+                    Some(package) => {
+                        eprintln!("Client: Unexpected package after the login: {package:?}");
+                    }
+                    None => {
+                        eprintln!("Client: Package reader stoped (probably lost connection to server), exiting");
+                        *world.disconnect_reason.lock().unwrap() = Some("Lost the connection to the server".to_string());
+                        return;
+                    },
                 }
             }
             update = client.recv() => {
                 match update {
                     Some(Update::Pos(camera)) => {
-                        let position_package = ClientPackagePlayerPosition {
+                        let position_package = ServerBound::PlayerPosition {
                             pos: camera.camera_position(),
                             pitch: camera.pitch(),
                             yaw: camera.yaw(),
                         };
-                        out_packages.send(position_package.to_box()).await.unwrap();
-                        let camera_pos = camera.camera_position();
-                        let camera_center = [
-                            (camera_pos[0] as i32).div_euclid(CHUNK_SIZE as i32),
-                            (camera_pos[2] as i32).div_euclid(CHUNK_SIZE as i32),
-                        ];
-                        if camera_center != current_world_center {
+                        out_packages.send(position_package).await.unwrap();
+                        let camera_pos = camera.camera_position().map(|x| x.floor() as i32);
+                        let (camera_center, _) = block_position_to_chunk_index(camera_pos);
+                        if current_world_center != Some(camera_center) {
                             // Remove chunks that are too far away
                             let loaded_chunks = {
                                 let mut unused_chunks = world.unused_chunks.lock().unwrap();
                                 let mut chunks = world.chunks.lock().unwrap();
 
-                                let to_far_chunks = chunks.extract_if(|pos, _| {
-                                    (pos[0] - camera_center[0]).abs() > VIEW_DISTANCE || (pos[2] - camera_center[1]).abs() > VIEW_DISTANCE
-                                });
+                                let to_far_chunks = chunks.extract_if(|pos, _| !in_view(*pos, camera_center));
 
                                 unused_chunks.extend(to_far_chunks.into_iter().map(|(_, chunk)| chunk));
 
                                 chunks.keys().cloned().collect::<HashSet<_>>()
                             };
-                            // Load new chunks
-                            for x in -VIEW_DISTANCE..=VIEW_DISTANCE {
-                                for z in -VIEW_DISTANCE..=VIEW_DISTANCE {
-                                    let pos = [camera_center[0] + x, 0, camera_center[1] + z];
-                                    if !loaded_chunks.contains(&pos) {
-                                        for y in -Y_RANGE..Y_RANGE {
-                                            let pos = [pos[0], y, pos[2]];
-                                            out_packages.send(request_chunk_package(pos)).await.unwrap();
-                                        }
-                                    }
+                            // Load new chunks, the closest ones first
+                            for offset in &view_offsets {
+                                let pos = [0, 1, 2].map(|i| camera_center[i] + offset[i]);
+                                if !loaded_chunks.contains(&pos) {
+                                    out_packages.send(ServerBound::RequestChunk { pos }).await.unwrap();
                                 }
                             }
-                            current_world_center = camera_center;
+                            current_world_center = Some(camera_center);
                         }
                     }
                     Some(Update::Block(pos, block)) => {
@@ -191,15 +203,10 @@ async fn manage_world(
                                 chunk.update_block(block_index, block, &blocks_config);
                             }
                         }
-                        let package = PackageBlockUpdate{
-                            pos,
-                            block,
-                            reserved: [0u8; 3],
-                        };
-                        let mut net_package = vec![0u8; 2 + std::mem::size_of::<PackageBlockUpdate>()];
-                        net_package[0..2].copy_from_slice(0x000Bu16.as_bytes());
-                        net_package[2..].copy_from_slice(package.as_bytes());
-                        out_packages.send(net_package.into_boxed_slice()).await.unwrap();
+                        out_packages.send(ServerBound::BlockUpdate { pos, block }).await.unwrap();
+                    }
+                    Some(Update::Command(line)) => {
+                        out_packages.send(ServerBound::Command { line }).await.unwrap();
                     }
                     Some(Update::Exit) => {
                         return;
@@ -211,13 +218,10 @@ async fn manage_world(
     }
 }
 
-async fn write_packages(
-    mut stream: OwnedWriteHalf,
-    mut input: tokio::sync::mpsc::Receiver<Box<[u8]>>,
-) {
+async fn write_packages(mut stream: Writer, mut input: tokio::sync::mpsc::Receiver<ServerBound>) {
     loop {
         if let Some(package) = input.recv().await {
-            if stream.write_all(&package).await.is_err() {
+            if stream.write_all(&package.encode()).await.is_err() {
                 eprintln!("Client: Writer failed to send package, exiting");
                 return;
             }
@@ -228,63 +232,12 @@ async fn write_packages(
     }
 }
 
-fn request_chunk_package(pos: [i32; 3]) -> Box<[u8]> {
-    let mut package = vec![0u8; 14];
-    package[0..2].copy_from_slice(0x000Au16.as_bytes());
-    package[2..].copy_from_slice(pos.as_bytes());
-    package.into_boxed_slice()
-}
-
 async fn read_packages(
-    mut reader: OwnedReadHalf,
-    chunk_loader: tokio::sync::mpsc::Sender<Package>,
+    mut reader: Reader,
+    chunk_loader: tokio::sync::mpsc::Sender<ClientBound>,
 ) -> Result<(), anyhow::Error> {
-    let mut package_type = 0u16;
     loop {
-        reader.read_exact(package_type.as_mut_bytes()).await?;
-
-        match package_type {
-            0x000A => {
-                //Chunk Data
-                let mut pos = [0i32; 3];
-                reader.read_exact(pos.as_mut_bytes()).await.unwrap();
-                let mut data = vec![0u8; 4096];
-                reader.read_exact(&mut data).await.unwrap();
-                chunk_loader.send(Package::Chunk(pos, data)).await.unwrap();
-            }
-            0x000B => {
-                let package = PackageBlockUpdate::new(&mut reader).await;
-                chunk_loader
-                    .send(Package::BlockUpdate(package))
-                    .await
-                    .unwrap();
-            }
-            0x000C => {
-                let player_pos = ServerPackagePlayerPosition::new(&mut reader).await;
-                chunk_loader
-                    .send(Package::PlayerPositionUpdate(player_pos))
-                    .await
-                    .unwrap();
-            }
-            0x0003 => {
-                // Other player logs in
-                let login_package = ServerPlayerLogin::new(&mut reader).await;
-                chunk_loader
-                    .send(Package::PlayerLogin(login_package))
-                    .await
-                    .unwrap();
-            }
-            0x0004 => {
-                // Player logs out
-                let logout_package = ServerPackageLogout::new(&mut reader).await;
-                chunk_loader
-                    .send(Package::PlayerLogout(logout_package))
-                    .await
-                    .unwrap();
-            }
-            _ => {
-                panic!("Client: Invalid Package type {package_type}")
-            }
-        }
+        let package = ClientBound::read(&mut reader).await?;
+        chunk_loader.send(package).await.unwrap();
     }
 }
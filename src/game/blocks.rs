@@ -4,7 +4,31 @@ use std::{collections::HashMap, path::Path};
 
 use serde::Deserialize;
 
-use crate::game::Direction;
+use crate::game::{Block, BlockState, BlockType, Direction};
+
+/// A property of the block state. The state of a block combines the values of all its
+/// properties in the order they are listed in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockProperty {
+    /// The axis along which the top and bottom textures point: y, x or z
+    Axis,
+    /// The horizontal direction of the front texture: +z, -x, -z or +x
+    Facing,
+    /// Closed or open, open blocks show the open texture on all sides, also on the front
+    Open,
+}
+
+impl BlockProperty {
+    /// Number of values of the property
+    pub fn values(self) -> u8 {
+        match self {
+            BlockProperty::Axis => 3,
+            BlockProperty::Facing => 4,
+            BlockProperty::Open => 2,
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct BlockConfig {
@@ -13,6 +37,14 @@ pub struct BlockConfig {
     pub texture: String,
     pub top_texture: String,
     pub bottom_texture: String,
+    /// Texture on the side the block is facing, only used with the `facing` property
+    #[serde(default)]
+    pub front_texture: Option<String>,
+    /// Texture on the sides of an open block, only used with the `open` property
+    #[serde(default)]
+    pub open_texture: Option<String>,
+    #[serde(default)]
+    pub properties: Vec<BlockProperty>,
 }
 
 pub struct BlocksConfig {
@@ -35,6 +67,9 @@ impl BlocksConfig {
                 texture: "".to_string(),
                 top_texture: "".to_string(),
                 bottom_texture: "".to_string(),
+                front_texture: None,
+                open_texture: None,
+                properties: Vec::new(),
             },
         );
 
@@ -42,17 +77,15 @@ impl BlocksConfig {
         let mut textures_vec = Vec::new();
 
         for block in &blocks[1..] {
-            if !textures.contains_key(&block.texture) {
-                textures.insert(block.texture.clone(), textures_vec.len() as u16);
-                textures_vec.push(block.texture.clone());
-            }
-            if !textures.contains_key(&block.top_texture) {
-                textures.insert(block.top_texture.clone(), textures_vec.len() as u16);
-                textures_vec.push(block.top_texture.clone());
-            }
-            if !textures.contains_key(&block.bottom_texture) {
-                textures.insert(block.bottom_texture.clone(), textures_vec.len() as u16);
-                textures_vec.push(block.bottom_texture.clone());
+            let block_textures = [&block.texture, &block.top_texture, &block.bottom_texture]
+                .into_iter()
+                .chain(&block.front_texture)
+                .chain(&block.open_texture);
+            for texture in block_textures {
+                if !textures.contains_key(texture) {
+                    textures.insert(texture.clone(), textures_vec.len() as u16);
+                    textures_vec.push(texture.clone());
+                }
             }
         }
 
@@ -61,12 +94,107 @@ impl BlocksConfig {
         (blocks_config, textures_vec)
     }
 
-    pub fn get_texture(&self, block_type: u8, dir: Direction) -> u16 {
-        let block = &self.blocks[block_type as usize];
-        let texture_name = match dir {
-            Direction::PosY => &block.top_texture,
-            Direction::NegY => &block.bottom_texture,
-            _ => &block.texture,
+    /// Id of the block with this name
+    pub fn id(&self, name: &str) -> Option<BlockType> {
+        self.blocks
+            .iter()
+            .position(|block| block.name == name)
+            .map(|id| id as BlockType)
+    }
+
+    /// Whether the id is in the config and the state exists for it
+    pub fn is_valid(&self, block: Block) -> bool {
+        (block.id as usize) < self.blocks.len()
+            && (block.state as usize) < self.state_count(block.id)
+    }
+
+    /// Number of different states of the block
+    pub fn state_count(&self, block_type: BlockType) -> usize {
+        self.blocks[block_type as usize]
+            .properties
+            .iter()
+            .map(|p| p.values() as usize)
+            .product()
+    }
+
+    /// Value of the property in the state of the block, None if the block does not have it
+    pub fn property(&self, block: Block, property: BlockProperty) -> Option<u8> {
+        let mut stride = 1;
+        for &p in &self.blocks[block.id as usize].properties {
+            if p == property {
+                return Some((block.state as usize / stride % p.values() as usize) as u8);
+            }
+            stride *= p.values() as usize;
+        }
+        None
+    }
+
+    /// Changes the value of the property, blocks without the property stay the same
+    pub fn with_property(&self, block: Block, property: BlockProperty, value: u8) -> Block {
+        let mut stride = 1;
+        for &p in &self.blocks[block.id as usize].properties {
+            if p == property {
+                let old = block.state as usize / stride % p.values() as usize;
+                let state = block.state as usize - old * stride + value as usize * stride;
+                return Block {
+                    id: block.id,
+                    state: state as BlockState,
+                };
+            }
+            stride *= p.values() as usize;
+        }
+        block
+    }
+
+    /// State of a newly placed block. It points along the axis of the clicked face
+    /// and faces the player, who looks along `view_direction`.
+    pub fn placement_state(
+        &self,
+        id: BlockType,
+        face_axis: usize,
+        view_direction: [f32; 3],
+    ) -> Block {
+        let mut block = Block::new(id);
+        // Axis values are y, x, z
+        let axis = [1, 0, 2][face_axis];
+        block = self.with_property(block, BlockProperty::Axis, axis);
+        let facing = if view_direction[0].abs() > view_direction[2].abs() {
+            if view_direction[0] > 0.0 { 1 } else { 3 }
+        } else if view_direction[2] > 0.0 {
+            2
+        } else {
+            0
+        };
+        self.with_property(block, BlockProperty::Facing, facing)
+    }
+
+    pub fn get_texture(&self, block: Block, dir: Direction) -> u16 {
+        let config = &self.blocks[block.id as usize];
+        let (top, bottom) = match self.property(block, BlockProperty::Axis) {
+            Some(1) => (Direction::PosX, Direction::NegX),
+            Some(2) => (Direction::PosZ, Direction::NegZ),
+            _ => (Direction::PosY, Direction::NegY),
+        };
+        let front = self.property(block, BlockProperty::Facing).map(|facing| {
+            [
+                Direction::PosZ,
+                Direction::NegX,
+                Direction::NegZ,
+                Direction::PosX,
+            ][facing as usize]
+        });
+        let open = self.property(block, BlockProperty::Open) == Some(1);
+
+        let texture_name = if dir == top {
+            &config.top_texture
+        } else if dir == bottom {
+            &config.bottom_texture
+        } else if open {
+            config.open_texture.as_ref().unwrap_or(&config.texture)
+        } else if front == Some(dir) {
+            config.front_texture.as_ref().unwrap_or(&config.texture)
+        } else {
+            &config.texture
         };
         self.textures
             .get(texture_name)
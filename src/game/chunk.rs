@@ -1,6 +1,6 @@
 use super::{ChunkIndex, LocalBlockIndex};
 use crate::{
-    game::{BlockType, blocks::BlocksConfig},
+    game::{Block, blocks::BlocksConfig, palette::PalettedBlocks},
     mygl::{GLToken, VAO, VBOWithStorage},
 };
 
@@ -8,9 +8,6 @@ use crate::game::Direction;
 
 pub const CHUNK_SIZE: u8 = 16;
 
-/// Range y chunks go from -Y_RANGE to Y_RANGE - 1
-pub const Y_RANGE: i32 = 4;
-
 pub fn block_position_to_chunk_index(pos: ChunkIndex) -> (ChunkIndex, LocalBlockIndex) {
     let chunk_pos = [
         pos[0].div_euclid(CHUNK_SIZE as i32),
@@ -25,28 +22,30 @@ pub fn block_position_to_chunk_index(pos: ChunkIndex) -> (ChunkIndex, LocalBlock
     (chunk_pos, block_pos)
 }
 
-/// Data of a chunk. The blocks are stored in a 1D array
+/// Index of a block in the storage of its chunk
+pub fn local_block_index(pos: LocalBlockIndex) -> usize {
+    let chunk_size_usize: usize = CHUNK_SIZE as usize;
+    pos[0] as usize * chunk_size_usize * chunk_size_usize
+        + pos[1] as usize * chunk_size_usize
+        + pos[2] as usize
+}
+
+/// Data of a chunk. The blocks are stored with a palette
 pub struct ChunkData {
-    blocks: Vec<u8>,
+    blocks: PalettedBlocks,
 }
 
 impl ChunkData {
-    pub fn new(data: Vec<u8>) -> Self {
-        ChunkData { blocks: data }
+    pub fn new(blocks: PalettedBlocks) -> Self {
+        ChunkData { blocks }
     }
 
-    pub fn get(&self, pos: LocalBlockIndex) -> u8 {
-        let chunk_size_usize: usize = CHUNK_SIZE as usize;
-        self.blocks[pos[0] as usize * chunk_size_usize * chunk_size_usize
-            + pos[1] as usize * chunk_size_usize
-            + pos[2] as usize]
+    pub fn get(&self, pos: LocalBlockIndex) -> Block {
+        self.blocks.get(local_block_index(pos))
     }
 
-    pub fn set(&mut self, pos: LocalBlockIndex, block: u8) {
-        let chunk_size_usize: usize = CHUNK_SIZE as usize;
-        self.blocks[pos[0] as usize * chunk_size_usize * chunk_size_usize
-            + pos[1] as usize * chunk_size_usize
-            + pos[2] as usize] = block;
+    pub fn set(&mut self, pos: LocalBlockIndex, block: Block) {
+        self.blocks.set(local_block_index(pos), block);
     }
 }
 
@@ -61,10 +60,9 @@ pub struct Chunk {
 }
 
 impl Chunk {
-    /// The next bytes in data have to represent the chunk data
-    pub fn new(glt: GLToken, position: [i32; 3], data: Vec<u8>) -> Self {
+    pub fn new(glt: GLToken, position: [i32; 3], blocks: PalettedBlocks) -> Self {
         let mut chunk = Chunk {
-            blocks: ChunkData::new(data),
+            blocks: ChunkData::new(blocks),
             position,
             vao: VAO::new(glt),
             vertex_pos: VBOWithStorage::new(glt),
@@ -84,11 +82,11 @@ impl Chunk {
     }
 
     pub fn new_empty(glt: GLToken) -> Self {
-        Self::new(glt, [0; 3], vec![])
+        Self::new(glt, [0; 3], PalettedBlocks::filled(Block::AIR))
     }
 
-    pub fn load(&mut self, data: Vec<u8>, position: [i32; 3]) {
-        self.blocks = ChunkData::new(data);
+    pub fn load(&mut self, blocks: PalettedBlocks, position: [i32; 3]) {
+        self.blocks = ChunkData::new(blocks);
         self.position = position;
     }
 
@@ -99,64 +97,64 @@ impl Chunk {
         for x in 0..CHUNK_SIZE {
             for y in 0..CHUNK_SIZE {
                 for z in 0..CHUNK_SIZE {
-                    if self.blocks.get([x, y, z]) > 0 {
-                        let block_type = self.blocks.get([x, y, z]);
-                        if z == CHUNK_SIZE - 1 || self.blocks.get([x, y, z + 1]) == 0 {
+                    let block = self.blocks.get([x, y, z]);
+                    if !block.is_air() {
+                        if z == CHUNK_SIZE - 1 || self.blocks.get([x, y, z + 1]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::PosZ,
                             );
                         }
-                        if z == 0 || self.blocks.get([x, y, z - 1]) == 0 {
+                        if z == 0 || self.blocks.get([x, y, z - 1]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::NegZ,
                             );
                         }
-                        if x == 0 || self.blocks.get([x - 1, y, z]) == 0 {
+                        if x == 0 || self.blocks.get([x - 1, y, z]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::NegX,
                             );
                         }
-                        if x == CHUNK_SIZE - 1 || self.blocks.get([x + 1, y, z]) == 0 {
+                        if x == CHUNK_SIZE - 1 || self.blocks.get([x + 1, y, z]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::PosX,
                             );
                         }
-                        if y == CHUNK_SIZE - 1 || self.blocks.get([x, y + 1, z]) == 0 {
+                        if y == CHUNK_SIZE - 1 || self.blocks.get([x, y + 1, z]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::PosY,
                             );
                         }
-                        if y == 0 || self.blocks.get([x, y - 1, z]) == 0 {
+                        if y == 0 || self.blocks.get([x, y - 1, z]).is_air() {
                             add_face(
                                 &mut vertex_pos,
                                 &mut texture_pos,
                                 block_config,
-                                block_type,
+                                block,
                                 [x, y, z],
                                 Direction::NegY,
                             );
@@ -173,7 +171,7 @@ impl Chunk {
     pub fn update_block(
         &mut self,
         pos: LocalBlockIndex,
-        block: BlockType,
+        block: Block,
         block_config: &BlocksConfig,
     ) {
         self.blocks.set(pos, block);
@@ -206,11 +204,11 @@ pub fn add_face(
     vertex_data: &mut Vec<u8>,
     texture_data: &mut Vec<f32>,
     block_config: &BlocksConfig,
-    block_type: u8,
+    block: Block,
     pos: [u8; 3],
     dir: Direction,
 ) {
-    let texture = block_config.get_texture(block_type, dir) as f32;
+    let texture = block_config.get_texture(block, dir) as f32;
 
     let (tex_x, tex_y) = (0.0, 0.0);
     let (size_x, size_y) = (1.0, 1.0);
@@ -334,3 +332,62 @@ pub fn add_face(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::game::blocks::BlockProperty;
+
+    const SIDES: [Direction; 4] = [
+        Direction::PosX,
+        Direction::NegX,
+        Direction::PosZ,
+        Direction::NegZ,
+    ];
+
+    /// Name of the texture add_face uses for every vertex of the face
+    fn face_texture<'a>(
+        config: &BlocksConfig,
+        textures: &'a [String],
+        block: Block,
+        dir: Direction,
+    ) -> &'a str {
+        let (mut vertices, mut texture_data) = (Vec::new(), Vec::new());
+        add_face(&mut vertices, &mut texture_data, config, block, [0; 3], dir);
+        let layers = texture_data.chunks(3).map(|uv| uv[2]).collect::<Vec<_>>();
+        assert_eq!(layers.len(), 6);
+        assert!(layers.iter().all(|&layer| layer == layers[0]));
+        &textures[layers[0] as usize]
+    }
+
+    #[test]
+    fn door_shows_its_front_and_open_textures() {
+        let (config, textures) = BlocksConfig::new(Path::new("config/blocks.json"));
+        let door = config.id("door").unwrap();
+
+        // Placed by a player looking along +x, the front points back to the player
+        let closed = config.placement_state(door, 0, [1.0, 0.0, 0.0]);
+        assert_eq!(config.property(closed, BlockProperty::Facing), Some(1));
+        assert_eq!(config.property(closed, BlockProperty::Open), Some(0));
+        for dir in SIDES {
+            let expected = if dir == Direction::NegX {
+                "door_front.png"
+            } else {
+                "wood.png"
+            };
+            assert_eq!(face_texture(&config, &textures, closed, dir), expected);
+        }
+
+        let open = config.with_property(closed, BlockProperty::Open, 1);
+        assert!(config.is_valid(open));
+        assert_eq!(config.property(open, BlockProperty::Facing), Some(1));
+        for dir in SIDES {
+            assert_eq!(face_texture(&config, &textures, open, dir), "door_open.png");
+        }
+        for dir in [Direction::PosY, Direction::NegY] {
+            assert_eq!(face_texture(&config, &textures, open, dir), "wood.png");
+        }
+    }
+}
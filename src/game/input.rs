@@ -11,3 +11,14 @@ pub struct Controls {
     pub right_click: bool,
     pub debug_screen: bool,
 }
+
+/// Editing of the command line, which takes the keyboard while it is open
+#[derive(Debug, Clone, Copy)]
+pub enum CommandLineInput {
+    Char(char),
+    Backspace,
+    /// Sends the line to the server as admin command and clears it
+    Submit,
+    /// Closes the command line
+    Close,
+}
@@ -1,16 +1,16 @@
 mod background;
-mod blocks;
+pub mod blocks;
 mod camera;
 pub mod chunk;
 mod input;
 pub mod misc;
 mod overlay;
+pub mod palette;
 mod player;
 mod renderer;
 mod skybox;
 mod world;
 
-use std::net::TcpStream;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -21,14 +21,14 @@ use winit::dpi::PhysicalSize;
 pub use camera::{Camera, FreeCamera};
 pub use chunk::CHUNK_SIZE;
 pub use chunk::Chunk;
-pub use chunk::Y_RANGE;
-pub use input::Controls;
+pub use input::{CommandLineInput, Controls};
 pub use renderer::Renderer;
 pub use world::World;
 
 use crate::game::blocks::BlocksConfig;
 use crate::mygl::BlockTextures;
 use crate::mygl::GLToken;
+use crate::net::secure::Connection;
 use crate::server::UID;
 
 use self::background::background_thread;
@@ -61,7 +61,45 @@ pub enum Key {
 
 pub type ChunkIndex = [i32; 3];
 pub type LocalBlockIndex = [u8; 3];
-pub type BlockType = u8;
+pub type BlockType = u16;
+/// State of a block like its orientation.
+/// Its meaning is defined by the properties of the block in config/blocks.json.
+pub type BlockState = u8;
+
+/// A block in the world, the id together with its state
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub id: BlockType,
+    pub state: BlockState,
+}
+
+impl Block {
+    pub const AIR: Block = Block { id: 0, state: 0 };
+    /// Bytes of a block in chunk data, see [`Block::to_bytes`]
+    pub const SIZE: usize = 3;
+
+    /// The block in its default state
+    pub fn new(id: BlockType) -> Self {
+        Block { id, state: 0 }
+    }
+
+    pub fn is_air(self) -> bool {
+        self.id == 0
+    }
+
+    /// The id as little endian u16 followed by the state
+    pub fn to_bytes(self) -> [u8; Block::SIZE] {
+        let [a, b] = self.id.to_le_bytes();
+        [a, b, self.state]
+    }
+
+    pub fn from_bytes(bytes: [u8; Block::SIZE]) -> Self {
+        Block {
+            id: BlockType::from_le_bytes([bytes[0], bytes[1]]),
+            state: bytes[2],
+        }
+    }
+}
 
 /// Represents the game state in the client.
 /// Renderer holds everything needed in the game loop.
@@ -89,7 +127,7 @@ impl Game {
     pub fn new(
         glt: GLToken,
         render_size: PhysicalSize<u32>,
-        tcp: TcpStream,
+        connection: Connection,
         uid: UID,
         name: String,
     ) -> Self {
@@ -114,15 +152,24 @@ impl Game {
 
         let (update_tx, update_rx) = tokio::sync::mpsc::channel(100);
 
-        let renderer = Renderer::new(glt, world.clone(), block_textures, render_size, update_tx);
+        let blocks_config = Arc::new(blocks_config);
+
+        let renderer = Renderer::new(
+            glt,
+            world.clone(),
+            blocks_config.clone(),
+            block_textures,
+            render_size,
+            update_tx,
+        );
 
         let chunk_loader_world = world.clone();
         let background_thread = std::thread::spawn(move || {
             background_thread(
-                tcp,
+                connection,
                 chunk_loader_world,
                 update_rx,
-                Arc::new(blocks_config),
+                blocks_config,
                 uid,
             )
         });
@@ -149,6 +196,20 @@ impl Game {
         self.renderer.keyboard_input(key, pressed);
     }
 
+    /// Opens the command line for admin commands, see [`crate::server::commands`]
+    pub fn open_command_line(&mut self) {
+        self.renderer.open_command_line();
+    }
+
+    /// While the command line is open all keys go to [`Game::command_line_input`]
+    pub fn command_line_open(&self) -> bool {
+        self.renderer.command_line_open()
+    }
+
+    pub fn command_line_input(&mut self, input: CommandLineInput) {
+        self.renderer.command_line_input(input);
+    }
+
     pub fn exit(self, glt: GLToken) {
         // Exit the background thread
         self.renderer.send_exit();
@@ -116,6 +116,12 @@ pub struct Overlay {
     cross_hair_vao: VAO,
     debug_screen: DebugScreen,
     player_names: HashMap<usize, Text>,
+    /// Shown in the middle of the screen once the connection to the server ended
+    disconnect_text: Option<Text>,
+    /// The typed line followed by the answers above it, empty while the command line is closed
+    command_line_texts: Vec<Text>,
+    /// The strings of `command_line_texts`, the texts are only recreated when they change
+    command_line_strings: Vec<String>,
     inv_aspect_ratio: f32,
 }
 
@@ -149,6 +155,9 @@ impl Overlay {
             cross_hair_vao: vao,
             debug_screen: DebugScreen::new(glt, text_renderer, inv_aspect),
             player_names: HashMap::new(),
+            disconnect_text: None,
+            command_line_texts: Vec::new(),
+            command_line_strings: Vec::new(),
             inv_aspect_ratio: inv_aspect,
         }
     }
@@ -177,6 +186,12 @@ impl Overlay {
         for text in self.player_names.values_mut() {
             text.update(glt, inv_aspect, text_renderer);
         }
+        if let Some(text) = &mut self.disconnect_text {
+            text.update(glt, inv_aspect, text_renderer);
+        }
+        for text in &mut self.command_line_texts {
+            text.update(glt, inv_aspect, text_renderer);
+        }
 
         self.inv_aspect_ratio = inv_aspect;
     }
@@ -190,7 +205,7 @@ impl Overlay {
     ) {
         let players = world.players.lock().unwrap();
 
-        let camera = &players.local_player.position + Player::camera_offset();
+        let camera = players.local_player.position + Player::camera_offset();
 
         text_renderer.bind_player_program(glt);
 
@@ -219,6 +234,7 @@ impl Overlay {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn draw(
         &mut self,
         glt: GLToken,
@@ -226,6 +242,7 @@ impl Overlay {
         world: &World,
         delta_t: f32,
         debug_screen: bool,
+        command_line: Option<&str>,
         projection_view: &Mat4,
     ) {
         self.cross_hair_program.bind(glt);
@@ -240,6 +257,86 @@ impl Overlay {
         if debug_screen {
             self.debug_screen.draw(glt, text_renderer, world, delta_t);
         }
+
+        self.draw_command_line(glt, text_renderer, world, command_line);
+        self.draw_disconnect_reason(glt, text_renderer, world);
+    }
+
+    /// Draws the typed line at the bottom left and the last answers of the server above it
+    fn draw_command_line(
+        &mut self,
+        glt: GLToken,
+        text_renderer: &TextRenderer,
+        world: &World,
+        command_line: Option<&str>,
+    ) {
+        let strings = match command_line {
+            Some(line) => {
+                let answers = world.command_answers.lock().unwrap();
+                std::iter::once(format!("> {line}"))
+                    .chain(answers.iter().rev().cloned())
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        if strings != self.command_line_strings {
+            for text in self.command_line_texts.drain(..) {
+                text.delete(glt);
+            }
+            for (i, string) in strings.iter().enumerate() {
+                // The answers come from the server and may contain characters the font does not have
+                let string = string
+                    .chars()
+                    .map(|c| if text_renderer.has_char(c) { c } else { '?' })
+                    .collect::<String>();
+                self.command_line_texts.push(text_renderer.create_text(
+                    glt,
+                    &string,
+                    (-1.0, -1.0 + 0.05 * i as f32),
+                    HorizontalTextAlignment::Left,
+                    VerticalTextAlignment::Bottom,
+                    0.025,
+                    self.inv_aspect_ratio,
+                ));
+            }
+            self.command_line_strings = strings;
+        }
+        if !self.command_line_texts.is_empty() {
+            text_renderer.bind_overlay_program(glt);
+            for text in &self.command_line_texts {
+                text.draw(glt);
+            }
+        }
+    }
+
+    fn draw_disconnect_reason(
+        &mut self,
+        glt: GLToken,
+        text_renderer: &TextRenderer,
+        world: &World,
+    ) {
+        if self.disconnect_text.is_none()
+            && let Some(reason) = world.disconnect_reason.lock().unwrap().as_ref()
+        {
+            // The reason comes from the server and may contain characters the font does not have
+            let reason = reason
+                .chars()
+                .map(|c| if text_renderer.has_char(c) { c } else { '?' })
+                .collect::<String>();
+            self.disconnect_text = Some(text_renderer.create_text(
+                glt,
+                &format!("Disconnected: {reason}"),
+                (0.0, 0.0),
+                HorizontalTextAlignment::Center,
+                VerticalTextAlignment::Middle,
+                0.04,
+                self.inv_aspect_ratio,
+            ));
+        }
+        if let Some(text) = &self.disconnect_text {
+            text_renderer.bind_overlay_program(glt);
+            text.draw(glt);
+        }
     }
 
     pub fn delete(self, glt: GLToken) {
@@ -250,6 +347,12 @@ impl Overlay {
         for text in self.player_names.into_values() {
             text.delete(glt);
         }
+        if let Some(text) = self.disconnect_text {
+            text.delete(glt);
+        }
+        for text in self.command_line_texts {
+            text.delete(glt);
+        }
     }
 }
 
@@ -0,0 +1,183 @@
+//! Palette based block storage of a chunk
+//!
+//! A chunk stores the blocks it contains (id and state) in a palette and every block as an
+//! index into the palette. The indices use as few bits as the palette allows, so a chunk of
+//! only air has no index data at all, while block ids can use the whole range of `BlockType`.
+
+use anyhow::{bail, ensure};
+
+use super::Block;
+
+/// Number of blocks in a chunk
+pub const CHUNK_VOLUME: usize = 4096;
+
+/// Bits per index needed for a palette with `len` entries.
+/// Only powers of two are used, so an index never crosses the border of a `u64`.
+fn bits_for(len: usize) -> u32 {
+    match len {
+        0..=1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PalettedBlocks {
+    palette: Vec<Block>,
+    /// Bits per index, 0 if the palette has a single entry
+    bits: u32,
+    /// Packed indices into the palette
+    data: Vec<u64>,
+}
+
+impl PalettedBlocks {
+    /// All blocks are `block`
+    pub fn filled(block: Block) -> Self {
+        PalettedBlocks {
+            palette: vec![block],
+            bits: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// `blocks` has to contain `CHUNK_VOLUME` blocks in the order of the chunk
+    pub fn from_blocks(blocks: &[Block]) -> Self {
+        assert_eq!(blocks.len(), CHUNK_VOLUME);
+        let mut palette = Vec::new();
+        for &block in blocks {
+            if !palette.contains(&block) {
+                palette.push(block);
+            }
+        }
+
+        let mut result = PalettedBlocks {
+            bits: bits_for(palette.len()),
+            palette,
+            data: Vec::new(),
+        };
+        result.data = vec![0; result.words()];
+        for (index, block) in blocks.iter().enumerate() {
+            let palette_index = result.palette.iter().position(|b| b == block).unwrap();
+            result.set_index(index, palette_index);
+        }
+        result
+    }
+
+    fn words(&self) -> usize {
+        (CHUNK_VOLUME * self.bits as usize).div_ceil(64)
+    }
+
+    fn get_index(&self, index: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        ((self.data[index / per_word] >> shift) & ((1 << self.bits) - 1)) as usize
+    }
+
+    fn set_index(&mut self, index: usize, palette_index: usize) {
+        if self.bits == 0 {
+            return;
+        }
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        let word = &mut self.data[index / per_word];
+        *word &= !(((1 << self.bits) - 1) << shift);
+        *word |= (palette_index as u64) << shift;
+    }
+
+    pub fn get(&self, index: usize) -> Block {
+        self.palette[self.get_index(index)]
+    }
+
+    pub fn set(&mut self, index: usize, block: Block) {
+        let palette_index = match self.palette.iter().position(|&b| b == block) {
+            Some(palette_index) => palette_index,
+            None => {
+                self.palette.push(block);
+                if bits_for(self.palette.len()) > self.bits {
+                    self.repack(bits_for(self.palette.len()));
+                }
+                self.palette.len() - 1
+            }
+        };
+        self.set_index(index, palette_index);
+    }
+
+    /// Changes the number of bits per index, keeping all blocks
+    fn repack(&mut self, bits: u32) {
+        let indices = (0..CHUNK_VOLUME)
+            .map(|i| self.get_index(i))
+            .collect::<Vec<_>>();
+        self.bits = bits;
+        self.data = vec![0; self.words()];
+        for (index, palette_index) in indices.into_iter().enumerate() {
+            self.set_index(index, palette_index);
+        }
+    }
+
+    /// All blocks in the order of the chunk
+    pub fn to_vec(&self) -> Vec<Block> {
+        (0..CHUNK_VOLUME).map(|i| self.get(i)).collect()
+    }
+
+    /// The palette length as u16, the palette (see [`Block::to_bytes`]), the bits per index as u8
+    /// and the packed indices, all little endian. Palette entries which are no longer used are dropped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let compact = Self::from_blocks(&self.to_vec());
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(compact.palette.len() as u16).to_le_bytes());
+        for block in &compact.palette {
+            bytes.extend_from_slice(&block.to_bytes());
+        }
+        bytes.push(compact.bits as u8);
+        for word in &compact.data {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads the format written by [`PalettedBlocks::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let Some((len, rest)) = bytes.split_first_chunk::<2>() else {
+            bail!("Chunk data is too short");
+        };
+        let len = u16::from_le_bytes(*len) as usize;
+        ensure!(len > 0, "Chunk palette is empty");
+        ensure!(rest.len() > len * Block::SIZE, "Chunk data is too short");
+        let (palette, rest) = rest.split_at(len * Block::SIZE);
+        let palette = palette
+            .chunks_exact(Block::SIZE)
+            .map(|b| Block::from_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        let bits = rest[0] as u32;
+        ensure!(
+            [0, 1, 2, 4, 8, 16].contains(&bits) && bits >= bits_for(len),
+            "Chunk data has an invalid number of bits per block"
+        );
+        let data = rest[1..]
+            .chunks_exact(8)
+            .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        let blocks = PalettedBlocks {
+            palette,
+            bits,
+            data,
+        };
+        ensure!(
+            rest.len() - 1 == blocks.words() * 8,
+            "Chunk data has an invalid length"
+        );
+        ensure!(
+            (0..CHUNK_VOLUME).all(|i| blocks.get_index(i) < len),
+            "Chunk data refers to a block outside of the palette"
+        );
+        Ok(blocks)
+    }
+}
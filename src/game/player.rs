@@ -8,7 +8,6 @@ use zerocopy::transmute;
 use crate::{
     game::misc::{CubeOutlines, extract_group_range},
     mygl::{GLToken, IndexBuffer, Program, Text, VAO, VBO},
-    net::ServerPackagePlayerPosition,
     server::UID,
 };
 
@@ -116,15 +115,14 @@ impl Players {
         self.players.retain(|p| p.uid != uid);
     }
 
-    pub fn update(&mut self, package: &ServerPackagePlayerPosition) {
+    pub fn update(&mut self, uid: UID, pos: [f64; 3], pitch: f32, yaw: f32) {
         for player in self.players.iter_mut() {
-            if player.uid == package.uid as usize {
-                player.update_pos_pitch_yaw(package.pos, package.pitch, package.yaw);
+            if player.uid == uid {
+                player.update_pos_pitch_yaw(pos, pitch, yaw);
             }
         }
-        if self.local_player.uid == package.uid as usize {
-            self.local_player
-                .update_pos_pitch_yaw(package.pos, package.pitch, package.yaw);
+        if self.local_player.uid == uid {
+            self.local_player.update_pos_pitch_yaw(pos, pitch, yaw);
         }
     }
 
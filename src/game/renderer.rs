@@ -6,10 +6,15 @@ use nalgebra_glm as glm;
 use crate::{
     game::skybox::SkyBox,
     mygl::{BlockTextures, GLToken, Program, TextRenderer, get_gl_string},
+    net::MAX_COMMAND_LENGTH,
 };
 
 use super::{
-    Camera, Controls, Key, World, background::Update, misc::CubeOutlines, overlay::Overlay,
+    Block, Camera, CommandLineInput, Controls, Key, World,
+    background::Update,
+    blocks::{BlockProperty, BlocksConfig},
+    misc::CubeOutlines,
+    overlay::Overlay,
 };
 
 const NEAR_PLAIN: f32 = 0.2;
@@ -19,10 +24,13 @@ const FAR_PLAIN: f32 = 300.0;
 /// It contains an Arc to the world, which is shared with the background thread.
 pub struct Renderer {
     world: Arc<World>,
+    blocks_config: Arc<BlocksConfig>,
     program: Program,
     block_textures: BlockTextures,
     projection: Mat4,
     controls: Controls,
+    /// The admin command which is typed, None if the command line is closed
+    command_line: Option<String>,
     cube_outlines: CubeOutlines,
     overlay: Overlay,
     skybox: SkyBox,
@@ -38,6 +46,7 @@ impl Renderer {
     pub fn new(
         glt: GLToken,
         world: Arc<World>,
+        blocks_config: Arc<BlocksConfig>,
         block_textures: BlockTextures,
         render_size: winit::dpi::PhysicalSize<u32>,
         updates: tokio::sync::mpsc::Sender<Update>,
@@ -62,7 +71,7 @@ impl Renderer {
             FAR_PLAIN,
         );
 
-        let skybox = SkyBox::new(glt, &Path::new("textures/skybox/cubemap_1.png"));
+        let skybox = SkyBox::new(glt, Path::new("textures/skybox/cubemap_1.png"));
 
         //let font = std::fs::read(Path::new("textures/font/MartianMono-Regular.otf"))
         //    .expect("Failed to read font file");
@@ -78,10 +87,12 @@ impl Renderer {
 
         Self {
             world,
+            blocks_config,
             program,
             block_textures,
             projection,
             controls: Controls::default(),
+            command_line: None,
             cube_outlines: CubeOutlines::new(glt),
             overlay: Overlay::new(glt, render_size, &text_renderer),
             skybox,
@@ -167,21 +178,38 @@ impl Renderer {
             if self.controls.left_click
                 && self.last_block_remove_place.elapsed().as_secs_f32() > 0.07
             {
-                let _ = self.updates.try_send(Update::Block(highlighted_block, 0));
+                let _ = self
+                    .updates
+                    .try_send(Update::Block(highlighted_block, Block::AIR));
                 self.last_block_remove_place = std::time::Instant::now();
             }
 
-            // Place block if right click
+            // Place block if right click, blocks which can be opened are opened or closed instead
             if self.controls.right_click
                 && self.last_block_remove_place.elapsed().as_secs_f32() > 0.15
             {
-                let mut block = highlighted_block;
-                block[direction] += if camera.view_direction()[direction] <= 0.0 {
-                    1
+                let clicked = self.world.get_block(highlighted_block);
+                let open =
+                    clicked.and_then(|b| self.blocks_config.property(b, BlockProperty::Open));
+                let update = if let (Some(clicked), Some(open)) = (clicked, open) {
+                    let toggled =
+                        self.blocks_config
+                            .with_property(clicked, BlockProperty::Open, 1 - open);
+                    Update::Block(highlighted_block, toggled)
                 } else {
-                    -1
+                    let mut block = highlighted_block;
+                    block[direction] += if camera.view_direction()[direction] <= 0.0 {
+                        1
+                    } else {
+                        -1
+                    };
+                    let view = camera.view_direction();
+                    let placed =
+                        self.blocks_config
+                            .placement_state(1, direction, [view.x, view.y, view.z]);
+                    Update::Block(block, placed)
                 };
-                let _ = self.updates.try_send(Update::Block(block, 1));
+                let _ = self.updates.try_send(update);
                 self.last_block_remove_place = std::time::Instant::now();
             }
 
@@ -213,6 +241,7 @@ impl Renderer {
             &self.world,
             delta_t,
             self.controls.debug_screen,
+            self.command_line.as_deref(),
             &projection_view,
         );
     }
@@ -240,6 +269,10 @@ impl Renderer {
 
     /// also manages clicking
     pub fn keyboard_input(&mut self, key: Key, pressed: bool) {
+        // The controls are released while the command line is open
+        if self.command_line.is_some() {
+            return;
+        }
         match key {
             Key::Backward => {
                 self.controls.backward = pressed;
@@ -272,6 +305,44 @@ impl Renderer {
             }
         }
     }
+    /// Releases all held keys, they are typed into the command line until it is closed
+    pub fn open_command_line(&mut self) {
+        self.controls = Controls {
+            debug_screen: self.controls.debug_screen,
+            ..Controls::default()
+        };
+        self.command_line = Some(String::new());
+    }
+
+    pub fn command_line_open(&self) -> bool {
+        self.command_line.is_some()
+    }
+
+    pub fn command_line_input(&mut self, input: CommandLineInput) {
+        let Some(line) = &mut self.command_line else {
+            return;
+        };
+        match input {
+            // Characters the font can not draw are not typed
+            CommandLineInput::Char(c) => {
+                if self.text_renderer.has_char(c) && line.len() + c.len_utf8() <= MAX_COMMAND_LENGTH
+                {
+                    line.push(c);
+                }
+            }
+            CommandLineInput::Backspace => {
+                line.pop();
+            }
+            CommandLineInput::Submit => {
+                let line = std::mem::take(line);
+                if !line.trim().is_empty() {
+                    let _ = self.updates.try_send(Update::Command(line));
+                }
+            }
+            CommandLineInput::Close => self.command_line = None,
+        }
+    }
+
     /// Sends a exit signal to the background
     pub fn send_exit(&self) {
         self.updates
@@ -4,48 +4,86 @@ use nalgebra_glm::{self as glm, DVec3};
 use zerocopy::transmute;
 
 use crate::{
-    game::{chunk::block_position_to_chunk_index, player::Player},
+    game::{Block, chunk::block_position_to_chunk_index, player::Player},
     mygl::{BlockTextures, GLToken, Program, TextRenderer},
 };
 
-use super::{CHUNK_SIZE, Camera, Chunk, Y_RANGE, player::Players};
+use super::{CHUNK_SIZE, Camera, Chunk, player::Players};
 
+/// Chunks are loaded in a sphere with this radius (in chunks) around the camera
 pub const VIEW_DISTANCE: i32 = 8;
 
-/// The maximum number of chunks that can be loaded at once
-const MAX_CHUNKS: usize =
-    4 * (VIEW_DISTANCE as usize + 1) * (VIEW_DISTANCE as usize + 1) * 2 * Y_RANGE as usize;
+/// Whether the chunk at `pos` is close enough to the camera chunk `center` to be loaded
+pub fn in_view(pos: [i32; 3], center: [i32; 3]) -> bool {
+    let distance_squared: i64 = (0..3)
+        .map(|i| (pos[i] as i64 - center[i] as i64).pow(2))
+        .sum();
+    distance_squared <= (VIEW_DISTANCE as i64).pow(2)
+}
+
+/// Offsets of all chunks in view relative to the camera chunk, nearest first
+pub fn view_offsets() -> Vec<[i32; 3]> {
+    let mut offsets = Vec::new();
+    for x in -VIEW_DISTANCE..=VIEW_DISTANCE {
+        for y in -VIEW_DISTANCE..=VIEW_DISTANCE {
+            for z in -VIEW_DISTANCE..=VIEW_DISTANCE {
+                if in_view([x, y, z], [0; 3]) {
+                    offsets.push([x, y, z]);
+                }
+            }
+        }
+    }
+    offsets.sort_by_key(|[x, y, z]| x * x + y * y + z * z);
+    offsets
+}
 
 pub struct World {
     pub chunks: Mutex<HashMap<[i32; 3], Chunk>>,
     pub unused_chunks: Mutex<Vec<Chunk>>,
     pub players: Mutex<Players>,
+    /// Why the connection to the server ended, shown on the screen
+    pub disconnect_reason: Mutex<Option<String>>,
+    /// The last lines of the answers to admin commands, shown with the command line
+    pub command_answers: Mutex<Vec<String>>,
 }
 
 impl World {
     pub fn new(glt: GLToken, local_player: Player, inv_aspect_ratio: f32) -> Self {
+        // Chunks outside of the view are dropped, so this is the maximum number of loaded chunks
+        let max_chunks = view_offsets().len();
         let mut unused_chunks = Vec::new();
-        for _ in 0..MAX_CHUNKS {
+        for _ in 0..max_chunks {
             unused_chunks.push(Chunk::new_empty(glt));
         }
 
         let players = Players::new(glt, local_player, inv_aspect_ratio);
 
         Self {
-            chunks: Mutex::new(HashMap::with_capacity(MAX_CHUNKS)),
+            chunks: Mutex::new(HashMap::with_capacity(max_chunks)),
             unused_chunks: Mutex::new(unused_chunks),
             players: Mutex::new(players),
+            disconnect_reason: Mutex::new(None),
+            command_answers: Mutex::new(Vec::new()),
         }
     }
 
     pub fn is_block(pos: [i32; 3], chunks: &HashMap<[i32; 3], Chunk>) -> bool {
         let (chunk_index, block_index) = block_position_to_chunk_index(pos);
         if let Some(chunk) = chunks.get(&chunk_index) {
-            return chunk.blocks.get(block_index) != 0;
+            return !chunk.blocks.get(block_index).is_air();
         }
         false
     }
 
+    /// The block at `pos`, None if its chunk is not loaded
+    pub fn get_block(&self, pos: [i32; 3]) -> Option<Block> {
+        let (chunk_index, block_index) = block_position_to_chunk_index(pos);
+        let chunks = self.chunks.lock().unwrap();
+        chunks
+            .get(&chunk_index)
+            .map(|chunk| chunk.blocks.get(block_index))
+    }
+
     pub fn is_block_at(pos: DVec3, chunks: &HashMap<[i32; 3], Chunk>) -> bool {
         let pos = pos.map(|x| x.floor() as i32);
         Self::is_block(transmute!(pos.data.0), chunks)
@@ -58,11 +96,7 @@ impl World {
         // Make sure the chunks are loaded around the player
 
         let player_pos = players.local_player.position.map(|x| x.floor() as i32);
-        let player_chunk_index = [
-            player_pos[0].div_euclid(CHUNK_SIZE as i32),
-            0,
-            player_pos[2].div_euclid(CHUNK_SIZE as i32),
-        ];
+        let (player_chunk_index, _) = block_position_to_chunk_index(transmute!(player_pos.data.0));
         if !self
             .chunks
             .lock()
@@ -1,11 +1,12 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-};
+use std::io::{Read, Write};
 
-use zerocopy::IntoBytes;
+use anyhow::{bail, ensure};
 
-use crate::net::read_string;
+use crate::net::{
+    ChunkEncoding, ClientBound, Handshake, MAX_PASSWORD_LENGTH, PROTOCOL_VERSION, Package,
+    ServerBound,
+    secure::{Connection, Transport},
+};
 
 /// Not sendable, use with phantom data
 #[allow(dead_code)]
@@ -28,6 +29,50 @@ pub fn first_none<T>(data: &[Option<T>]) -> Option<usize> {
     None
 }
 
+/// Replaces the file at `path` with `contents` without ever leaving a partially written file.
+/// The data is written to a temporary file next to it, which is then renamed.
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, contents, false)
+}
+
+/// Like [`write_atomic`], but only the owner can read the file, also while it is written
+pub fn write_atomic_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, contents, true)
+}
+
+fn write_atomic_with(
+    path: &std::path::Path,
+    contents: &[u8],
+    private: bool,
+) -> std::io::Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = std::path::PathBuf::from(temp_name);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        // A leftover temporary file would keep its permissions
+        match std::fs::remove_file(&temp_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        options.create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    // Make the rename itself durable, a relative file name has the empty path as parent
+    if let Some(directory) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
 pub fn start_server(world_directory: &str) -> (std::process::Child, String) {
     let mut child = std::process::Command::new("cargo")
         .args([
@@ -68,32 +113,42 @@ pub fn start_server(world_directory: &str) -> (std::process::Child, String) {
     (child, bind)
 }
 
-pub fn login(bind: &str, username: &str) -> (TcpStream, u64) {
-    let mut stream = TcpStream::connect(bind).expect("Client: Could not connect to server");
-    //login package
-    let len = username.len();
-    assert!(len <= u16::MAX as usize);
-    stream.write_all(0x0001u16.as_bytes()).unwrap();
-    stream.write_all((len as u16).as_bytes()).unwrap();
-    stream.write_all(username.as_bytes()).unwrap();
-
-    let mut answer = 0u16;
-    stream.read_exact(answer.as_mut_bytes()).unwrap();
-
-    let uid = match answer {
-        0x00001 => {
-            //Login Failed
-            panic!("Client: Login failed:{}", read_string(&mut stream));
-        }
-        0x00002 => {
-            //Login success
-            let mut uid = 0u64;
-            stream.read_exact(uid.as_mut_bytes()).unwrap();
-            uid
-        }
-        _ => {
-            panic!("Client:Invalid package");
-        }
+/// Sends the handshake and logs in, the password registers the name if it is new.
+/// Names from before passwords existed get the password as well on the internal server.
+/// Returns the uid and the chunk encoding the server will use.
+/// Fails with the reason given by the server if the login is rejected.
+pub fn login(
+    bind: &str,
+    username: &str,
+    password: &str,
+    transport: &Transport,
+    handshake: Handshake,
+) -> anyhow::Result<(Connection, u64, ChunkEncoding)> {
+    let mut stream = Connection::connect(bind, transport)?;
+
+    stream.write_all(&ServerBound::Handshake(handshake).encode())?;
+    let handshake = match ClientBound::read_blocking(&mut stream)? {
+        ClientBound::LoginFailure { reason } => bail!("Login failed: {reason}"),
+        ClientBound::Handshake(handshake) => handshake,
+        answer => bail!("Invalid answer to the handshake: {answer:?}"),
+    };
+    ensure!(
+        handshake.version == PROTOCOL_VERSION,
+        "Server uses the incompatible protocol version {}",
+        handshake.version
+    );
+
+    ensure!(
+        password.len() <= MAX_PASSWORD_LENGTH,
+        "The password is longer than {MAX_PASSWORD_LENGTH} bytes"
+    );
+    let name = username.to_string();
+    let password = password.to_string();
+    stream.write_all(&ServerBound::Login { name, password }.encode())?;
+    let uid = match ClientBound::read_blocking(&mut stream)? {
+        ClientBound::LoginFailure { reason } => bail!("Login failed: {reason}"),
+        ClientBound::LoginSuccess { uid } => uid,
+        answer => bail!("Invalid answer to the login: {answer:?}"),
     };
-    (stream, uid)
+    Ok((stream, uid, handshake.chunk_encoding()))
 }
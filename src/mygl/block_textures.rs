@@ -11,9 +11,9 @@ impl BlockTextures {
     pub fn new(glt: GLToken, pngs: &[&str]) -> Self {
         let texture_array =
             super::texture_array::TextureArray::new(glt, gl::LINEAR, gl::LINEAR, gl::CLAMP_TO_EDGE);
-        texture_array.upload_png(glt, pngs.into_iter().copied(), 4);
+        texture_array.upload_png(glt, pngs.iter().copied(), 4);
         let mut positions = HashMap::new();
-        for (i, png) in pngs.into_iter().enumerate() {
+        for (i, png) in pngs.iter().enumerate() {
             // Store the position of the texture in the atlas
             positions.insert(png.to_string(), i as f32);
         }
@@ -152,6 +152,7 @@ impl VAO {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn attrib_pointer<T: GLType>(
         &mut self,
         glt: GLToken,
@@ -144,6 +144,12 @@ impl TextRenderer {
         }
     }
 
+    /// Whether the character is in the font atlas, texts must not contain other characters
+    pub fn has_char(&self, char: char) -> bool {
+        self.texture_coordinates.contains_key(&char)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn create_text(
         &self,
         glt: GLToken,
@@ -173,7 +179,7 @@ impl TextRenderer {
             vbo_texture,
             vbo_vertex,
         };
-        text.update(glt, inv_aspect_ratio, &self);
+        text.update(glt, inv_aspect_ratio, self);
         text
     }
 
@@ -0,0 +1,69 @@
+//! Encodings of the chunk data in chunk packages
+//!
+//! Most chunks are all air or all stone, so long runs of the same block are common.
+//! The run-length encoding stores every run as a little endian `u16` length followed by the
+//! block, see [`Block::to_bytes`].
+
+use anyhow::{bail, ensure};
+
+use crate::game::Block;
+
+/// Bytes of one run in the run-length encoding
+const RUN_SIZE: usize = 2 + Block::SIZE;
+
+/// How the server encodes chunk data for a client, negotiated in the [`Handshake`](super::Handshake)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChunkEncoding {
+    /// 4096 raw blocks in a 0x000A package
+    Raw = 0,
+    /// Run-length encoded in a 0x000D package
+    RunLength = 1,
+}
+
+pub fn encode_run_length(blocks: &[Block]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut rest = blocks;
+    while let Some(&block) = rest.first() {
+        let run = rest
+            .iter()
+            .take(u16::MAX as usize)
+            .take_while(|&&b| b == block)
+            .count();
+        encoded.extend_from_slice(&(run as u16).to_le_bytes());
+        encoded.extend_from_slice(&block.to_bytes());
+        rest = &rest[run..];
+    }
+    encoded
+}
+
+/// Length of the data [`encode_run_length`] returns, without encoding it
+pub fn run_length_size(blocks: &[Block]) -> usize {
+    blocks
+        .chunk_by(|a, b| a == b)
+        .map(|run| run.len().div_ceil(u16::MAX as usize) * RUN_SIZE)
+        .sum()
+}
+
+/// Fails if the data does not decode to exactly `blocks.len()` blocks
+pub fn decode_run_length(encoded: &[u8], blocks: &mut [Block]) -> anyhow::Result<()> {
+    ensure!(
+        encoded.len().is_multiple_of(RUN_SIZE),
+        "Run-length data has an invalid length"
+    );
+    let mut filled = 0;
+    for run in encoded.chunks_exact(RUN_SIZE) {
+        let length = u16::from_le_bytes([run[0], run[1]]) as usize;
+        let block = Block::from_bytes(run[2..].try_into().unwrap());
+        let Some(target) = blocks.get_mut(filled..filled + length) else {
+            bail!("Run-length data is longer than the chunk");
+        };
+        target.fill(block);
+        filled += length;
+    }
+    ensure!(
+        filled == blocks.len(),
+        "Run-length data is shorter than the chunk"
+    );
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! The handshake which starts every connection
+//!
+//! Before the login the client sends a 0x0005 package with its protocol version and the
+//! capabilities it supports. If the server speaks the same version it answers with its own
+//! 0x0005 package, which lists the capabilities both sides support. Otherwise it answers with
+//! the login failure package 0x0001 and closes the connection.
+//!
+//! The package is the version as u32, the number of capabilities as u16 and every capability
+//! as a string.
+
+use super::ChunkEncoding;
+
+/// Version of the network protocol. Every change to a package has to increase it,
+/// additions which are only used when both sides support them should be capabilities instead.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Chunks may be sent run-length encoded, see [`ChunkEncoding::RunLength`]
+pub const RUN_LENGTH_CHUNKS: &str = "run_length_chunks";
+
+/// All capabilities supported by this build
+pub const CAPABILITIES: &[&str] = &[RUN_LENGTH_CHUNKS];
+
+/// Longest capability name which is accepted
+pub const MAX_CAPABILITY_LENGTH: usize = 64;
+
+/// Most capabilities a handshake may list
+pub const MAX_CAPABILITIES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Handshake {
+    /// The handshake of this build
+    pub fn new() -> Self {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Leaves out the capability, e.g. [`RUN_LENGTH_CHUNKS`] to get raw chunks
+    pub fn without(mut self, capability: &str) -> Self {
+        self.capabilities.retain(|c| c != capability);
+        self
+    }
+
+    /// The answer of the server, it only contains the capabilities both sides support
+    pub fn answer(&self) -> Self {
+        let mut answer = Self::new();
+        answer
+            .capabilities
+            .retain(|c| self.capabilities.contains(c));
+        answer
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Chunk encoding to use after the handshake was answered
+    pub fn chunk_encoding(&self) -> ChunkEncoding {
+        if self.supports(RUN_LENGTH_CHUNKS) {
+            ChunkEncoding::RunLength
+        } else {
+            ChunkEncoding::Raw
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_encoding_is_negotiated() {
+        assert_eq!(
+            Handshake::new().answer().chunk_encoding(),
+            ChunkEncoding::RunLength
+        );
+        let raw = Handshake::new().without(RUN_LENGTH_CHUNKS);
+        assert_eq!(raw.answer().chunk_encoding(), ChunkEncoding::Raw);
+    }
+}
@@ -1,134 +1,755 @@
+//! All packages of the network protocol and their encoding
+//!
+//! Every package starts with its id as little endian u16, followed by its fields in order.
+//! Numbers are little endian, strings are a u16 length followed by UTF-8 and blocks are
+//! encoded with [`Block::to_bytes`]. Packages have no length prefix, so the decoder reports
+//! how many bytes are missing and the readers never read past the end of a package.
+
 use std::sync::Arc;
 
-use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
-use zerocopy::{FromBytes, Immutable, IntoBytes};
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::server::{Command, ServerCommand, UID};
+use crate::game::Block;
+use crate::game::palette::CHUNK_VOLUME;
 
-pub trait Package: Default + IntoBytes + FromBytes + Immutable {
-    fn id() -> u16;
-    async fn new(stream: &mut OwnedReadHalf) -> Self {
-        let mut package = Self::default();
-        stream.read_exact(package.as_mut_bytes()).await.unwrap();
-        package
-    }
+use super::{
+    ChunkEncoding, Handshake, MAX_CAPABILITIES, MAX_CAPABILITY_LENGTH, decode_run_length,
+    encode_run_length,
+};
+
+/// Longest player name in bytes
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Longest password in bytes
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Longest reason of a login failure or a disconnect in bytes
+pub const MAX_REASON_LENGTH: usize = 1024;
+
+/// Longest admin command line in bytes
+pub const MAX_COMMAND_LENGTH: usize = 256;
 
-    async fn handle(&self, command: &ServerCommand, uid: UID);
+/// Longest answer to an admin command in bytes
+pub const MAX_ANSWER_LENGTH: usize = 4096;
 
-    async fn read_and_handle(stream: &mut OwnedReadHalf, command: &ServerCommand, uid: UID) {
-        let package = Self::new(stream).await;
-        package.handle(command, uid).await;
+/// Cuts the reason to [`MAX_REASON_LENGTH`] at a character boundary, longer ones can not be sent
+pub fn truncate_reason(reason: String) -> String {
+    truncate(reason, MAX_REASON_LENGTH)
+}
+
+/// Cuts the answer to [`MAX_ANSWER_LENGTH`] at a character boundary, longer ones can not be sent
+pub fn truncate_answer(answer: String) -> String {
+    truncate(answer, MAX_ANSWER_LENGTH)
+}
+
+fn truncate(mut string: String, max_length: usize) -> String {
+    if string.len() > max_length {
+        let end = (0..=max_length)
+            .rev()
+            .find(|&i| string.is_char_boundary(i))
+            .unwrap_or(0);
+        string.truncate(end);
     }
+    string
+}
+
+/// Packages sent from the server to the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientBound {
+    /// 0x0001, the connection is closed afterwards
+    LoginFailure { reason: String },
+    /// 0x0002
+    LoginSuccess { uid: u64 },
+    /// 0x0003, another player logged in
+    PlayerLogin { uid: u64, name: String },
+    /// 0x0004
+    PlayerLogout { uid: u64 },
+    /// 0x0005, the answer to the handshake of the client
+    Handshake(Handshake),
+    /// 0x0006, the server closes the connection after the login, e.g. because the player was kicked
+    Disconnect { reason: String },
+    /// 0x0007, the answer to an admin command of the player, it may have several lines
+    CommandAnswer { answer: String },
+    /// 0x000A if raw, 0x000D if run-length encoded. `blocks` contains the whole chunk.
+    Chunk {
+        pos: [i32; 3],
+        blocks: Vec<Block>,
+        encoding: ChunkEncoding,
+    },
+    /// 0x000B, the block at the position changed
+    BlockUpdate { pos: [i32; 3], block: Block },
+    /// 0x000E, the server did not accept the block update of the client at the position,
+    /// `block` is the block which is there
+    BlockUpdateRejected { pos: [i32; 3], block: Block },
+    /// 0x000C
+    PlayerPosition {
+        uid: u64,
+        pos: [f64; 3],
+        pitch: f32,
+        yaw: f32,
+    },
+}
+
+/// Packages sent from the client to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerBound {
+    /// 0x0001, only accepted after the handshake. The password is sent in plain text.
+    Login { name: String, password: String },
+    /// 0x0005, the first package of every connection
+    Handshake(Handshake),
+    /// 0x0007, a line of the admin commands, see [`crate::server::commands`]
+    Command { line: String },
+    /// 0x000A
+    RequestChunk { pos: [i32; 3] },
+    /// 0x000B, the server answers with the block which is there afterwards
+    BlockUpdate { pos: [i32; 3], block: Block },
+    /// 0x000C, position of the camera
+    PlayerPosition { pos: [f64; 3], pitch: f32, yaw: f32 },
+}
+
+/// Result of decoding a package from the start of a buffer
+#[derive(Debug, PartialEq)]
+pub enum Decoded<T> {
+    Complete(T),
+    /// The package continues after the buffer, at least this many more bytes are needed
+    Incomplete(usize),
+}
+
+pub trait Package: Sized {
+    /// The package including its id
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decodes the package at the start of `bytes`, bytes after the package are ignored.
+    /// Fails if the bytes are not a valid package.
+    fn decode(bytes: &[u8]) -> anyhow::Result<Decoded<Self>>;
 
     fn to_arc(&self) -> Arc<[u8]> {
-        let mut bytes = vec![0u8; std::mem::size_of::<Self>() + 2];
-        bytes[0..2].copy_from_slice(&Self::id().to_le_bytes());
-        bytes[2..].copy_from_slice(self.as_bytes());
-        bytes.into()
+        self.encode().into()
     }
-    fn to_box(&self) -> Box<[u8]> {
-        let mut bytes = vec![0u8; std::mem::size_of::<Self>() + 2];
-        bytes[0..2].copy_from_slice(&Self::id().to_le_bytes());
-        bytes[2..].copy_from_slice(self.as_bytes());
-        bytes.into()
+
+    /// Reads exactly one package from the stream
+    async fn read(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Self> {
+        let mut buffer = Vec::new();
+        loop {
+            match Self::decode(&buffer)? {
+                Decoded::Complete(package) => return Ok(package),
+                Decoded::Incomplete(missing) => {
+                    let start = buffer.len();
+                    buffer.resize(start + missing, 0);
+                    stream.read_exact(&mut buffer[start..]).await?;
+                }
+            }
+        }
+    }
+
+    /// Reads exactly one package from a sync stream
+    fn read_blocking(stream: &mut impl std::io::Read) -> anyhow::Result<Self> {
+        let mut buffer = Vec::new();
+        loop {
+            match Self::decode(&buffer)? {
+                Decoded::Complete(package) => return Ok(package),
+                Decoded::Incomplete(missing) => {
+                    let start = buffer.len();
+                    buffer.resize(start + missing, 0);
+                    stream.read_exact(&mut buffer[start..])?;
+                }
+            }
+        }
     }
 }
 
-impl Package for ClientPackagePlayerPosition {
-    fn id() -> u16 {
-        0x000C
+impl Package for ClientBound {
+    fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        match self {
+            ClientBound::LoginFailure { reason } => {
+                e.u16(0x0001);
+                e.string(reason, MAX_REASON_LENGTH);
+            }
+            ClientBound::LoginSuccess { uid } => {
+                e.u16(0x0002);
+                e.u64(*uid);
+            }
+            ClientBound::PlayerLogin { uid, name } => {
+                e.u16(0x0003);
+                e.string(name, MAX_NAME_LENGTH);
+                e.u64(*uid);
+            }
+            ClientBound::PlayerLogout { uid } => {
+                e.u16(0x0004);
+                e.u64(*uid);
+            }
+            ClientBound::Handshake(handshake) => {
+                e.u16(0x0005);
+                e.handshake(handshake);
+            }
+            ClientBound::Disconnect { reason } => {
+                e.u16(0x0006);
+                e.string(reason, MAX_REASON_LENGTH);
+            }
+            ClientBound::CommandAnswer { answer } => {
+                e.u16(0x0007);
+                e.string(answer, MAX_ANSWER_LENGTH);
+            }
+            ClientBound::Chunk {
+                pos,
+                blocks,
+                encoding: ChunkEncoding::Raw,
+            } => {
+                assert_eq!(blocks.len(), CHUNK_VOLUME);
+                e.u16(0x000A);
+                e.position(pos);
+                for block in blocks {
+                    e.block(block);
+                }
+            }
+            ClientBound::Chunk {
+                pos,
+                blocks,
+                encoding: ChunkEncoding::RunLength,
+            } => {
+                assert_eq!(blocks.len(), CHUNK_VOLUME);
+                let encoded = encode_run_length(blocks);
+                e.u16(0x000D);
+                e.position(pos);
+                e.u16(encoded.len() as u16);
+                e.bytes.extend_from_slice(&encoded);
+            }
+            ClientBound::BlockUpdate { pos, block } => {
+                e.u16(0x000B);
+                e.position(pos);
+                e.block(block);
+                // Reserved
+                e.bytes.push(0);
+            }
+            ClientBound::BlockUpdateRejected { pos, block } => {
+                e.u16(0x000E);
+                e.position(pos);
+                e.block(block);
+            }
+            ClientBound::PlayerPosition {
+                uid,
+                pos,
+                pitch,
+                yaw,
+            } => {
+                e.u16(0x000C);
+                e.u64(*uid);
+                e.float_position(pos);
+                e.f32(*pitch);
+                e.f32(*yaw);
+            }
+        }
+        e.bytes
     }
-    async fn handle(&self, command: &ServerCommand, uid: UID) {
-        command
-            .send((uid, Command::PlayerPosition(self.pos, self.pitch, self.yaw)))
-            .await
-            .unwrap();
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Decoded<Self>> {
+        decode_with(bytes, |d| {
+            Ok(match d.u16()? {
+                0x0001 => ClientBound::LoginFailure {
+                    reason: d.string(MAX_REASON_LENGTH)?,
+                },
+                0x0002 => ClientBound::LoginSuccess { uid: d.u64()? },
+                0x0003 => {
+                    let name = d.string(MAX_NAME_LENGTH)?;
+                    ClientBound::PlayerLogin {
+                        uid: d.u64()?,
+                        name,
+                    }
+                }
+                0x0004 => ClientBound::PlayerLogout { uid: d.u64()? },
+                0x0005 => ClientBound::Handshake(d.handshake()?),
+                0x0006 => ClientBound::Disconnect {
+                    reason: d.string(MAX_REASON_LENGTH)?,
+                },
+                0x0007 => ClientBound::CommandAnswer {
+                    answer: d.string(MAX_ANSWER_LENGTH)?,
+                },
+                0x000A => {
+                    let pos = d.position()?;
+                    let data = d.take(CHUNK_VOLUME * Block::SIZE)?;
+                    let blocks = data
+                        .chunks_exact(Block::SIZE)
+                        .map(|b| Block::from_bytes(b.try_into().unwrap()))
+                        .collect();
+                    ClientBound::Chunk {
+                        pos,
+                        blocks,
+                        encoding: ChunkEncoding::Raw,
+                    }
+                }
+                0x000D => {
+                    let pos = d.position()?;
+                    let length = d.u16()? as usize;
+                    let mut blocks = vec![Block::AIR; CHUNK_VOLUME];
+                    decode_run_length(d.take(length)?, &mut blocks)?;
+                    ClientBound::Chunk {
+                        pos,
+                        blocks,
+                        encoding: ChunkEncoding::RunLength,
+                    }
+                }
+                0x000B => {
+                    let package = ClientBound::BlockUpdate {
+                        pos: d.position()?,
+                        block: d.block()?,
+                    };
+                    // Reserved
+                    d.take(1)?;
+                    package
+                }
+                0x000E => ClientBound::BlockUpdateRejected {
+                    pos: d.position()?,
+                    block: d.block()?,
+                },
+                0x000C => ClientBound::PlayerPosition {
+                    uid: d.u64()?,
+                    pos: d.float_position()?,
+                    pitch: d.angle()?,
+                    yaw: d.angle()?,
+                },
+                id => return Err(anyhow!("Unknown package id {id:#06x}").into()),
+            })
+        })
     }
 }
 
-impl Package for ServerPackagePlayerPosition {
-    fn id() -> u16 {
-        0x000C
+impl Package for ServerBound {
+    fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        match self {
+            ServerBound::Login { name, password } => {
+                e.u16(0x0001);
+                e.string(name, MAX_NAME_LENGTH);
+                e.string(password, MAX_PASSWORD_LENGTH);
+            }
+            ServerBound::Handshake(handshake) => {
+                e.u16(0x0005);
+                e.handshake(handshake);
+            }
+            ServerBound::Command { line } => {
+                e.u16(0x0007);
+                e.string(line, MAX_COMMAND_LENGTH);
+            }
+            ServerBound::RequestChunk { pos } => {
+                e.u16(0x000A);
+                e.position(pos);
+            }
+            ServerBound::BlockUpdate { pos, block } => {
+                e.u16(0x000B);
+                e.position(pos);
+                e.block(block);
+                // Reserved
+                e.bytes.push(0);
+            }
+            ServerBound::PlayerPosition { pos, pitch, yaw } => {
+                e.u16(0x000C);
+                e.float_position(pos);
+                e.f32(*pitch);
+                e.f32(*yaw);
+            }
+        }
+        e.bytes
     }
-    async fn handle(&self, _command: &ServerCommand, _uid: UID) {
-        panic!("ServerPackagePlayerPosition should not be received by the server");
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Decoded<Self>> {
+        decode_with(bytes, |d| {
+            Ok(match d.u16()? {
+                0x0001 => ServerBound::Login {
+                    name: d.string(MAX_NAME_LENGTH)?,
+                    password: d.string(MAX_PASSWORD_LENGTH)?,
+                },
+                0x0005 => ServerBound::Handshake(d.handshake()?),
+                0x0007 => ServerBound::Command {
+                    line: d.string(MAX_COMMAND_LENGTH)?,
+                },
+                0x000A => ServerBound::RequestChunk { pos: d.position()? },
+                0x000B => {
+                    let package = ServerBound::BlockUpdate {
+                        pos: d.position()?,
+                        block: d.block()?,
+                    };
+                    // Reserved
+                    d.take(1)?;
+                    package
+                }
+                0x000C => ServerBound::PlayerPosition {
+                    pos: d.float_position()?,
+                    pitch: d.angle()?,
+                    yaw: d.angle()?,
+                },
+                id => return Err(anyhow!("Unknown package id {id:#06x}").into()),
+            })
+        })
     }
 }
 
-impl Package for PackageBlockUpdate {
-    fn id() -> u16 {
-        0x000B
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
     }
-    async fn handle(&self, _command: &ServerCommand, uid: UID) {
-        todo!("Handle block update for uid: {uid}");
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
     }
-}
 
-impl Package for ServerPackageLogout {
-    fn id() -> u16 {
-        0x0004
+    fn position(&mut self, pos: &[i32; 3]) {
+        for x in pos {
+            self.bytes.extend_from_slice(&x.to_le_bytes());
+        }
     }
-    async fn handle(&self, _command: &ServerCommand, _uid: UID) {
-        panic!("ServerPackageLogout should not be received by the server");
+
+    fn float_position(&mut self, pos: &[f64; 3]) {
+        for x in pos {
+            self.bytes.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+
+    fn string(&mut self, string: &str, max_length: usize) {
+        assert!(string.len() <= max_length);
+        self.u16(string.len() as u16);
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.bytes.extend_from_slice(&block.to_bytes());
+    }
+
+    fn handshake(&mut self, handshake: &Handshake) {
+        self.u32(handshake.version);
+        assert!(handshake.capabilities.len() <= MAX_CAPABILITIES);
+        self.u16(handshake.capabilities.len() as u16);
+        for capability in &handshake.capabilities {
+            self.string(capability, MAX_CAPABILITY_LENGTH);
+        }
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Default, IntoBytes, FromBytes, Immutable)]
-pub struct PackageBlockUpdate {
-    pub pos: [i32; 3],
-    pub block: u8,
-    pub reserved: [u8; 3],
+enum DecodeError {
+    /// Number of missing bytes
+    Incomplete(usize),
+    Invalid(anyhow::Error),
 }
 
-#[repr(C)]
-#[derive(Debug, Default, IntoBytes, FromBytes, Immutable)]
-pub struct ClientPackagePlayerPosition {
-    pub pos: [f64; 3],
-    pub pitch: f32,
-    pub yaw: f32,
+impl From<anyhow::Error> for DecodeError {
+    fn from(e: anyhow::Error) -> Self {
+        DecodeError::Invalid(e)
+    }
 }
 
-#[repr(C)]
-#[derive(Debug, Default, IntoBytes, FromBytes, Immutable)]
-pub struct ServerPackagePlayerPosition {
-    pub uid: u64,
-    pub pos: [f64; 3],
-    pub pitch: f32,
-    pub yaw: f32,
+fn decode_with<T>(
+    bytes: &[u8],
+    decode: impl FnOnce(&mut Decoder) -> Result<T, DecodeError>,
+) -> anyhow::Result<Decoded<T>> {
+    match decode(&mut Decoder { bytes }) {
+        Ok(package) => Ok(Decoded::Complete(package)),
+        Err(DecodeError::Incomplete(missing)) => Ok(Decoded::Incomplete(missing)),
+        Err(DecodeError::Invalid(e)) => Err(e),
+    }
 }
 
-#[repr(C)]
-#[derive(Debug, Default, IntoBytes, FromBytes, Immutable)]
-pub struct ServerPackageLogout {
-    pub uid: u64,
+/// Reads the fields of a package from the front of the remaining bytes
+struct Decoder<'a> {
+    bytes: &'a [u8],
 }
 
-pub struct ServerPlayerLogin {
-    pub uid: u64,
-    pub name: String,
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::Incomplete(n - self.bytes.len()));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Fails for infinite and NaN angles
+    fn angle(&mut self) -> Result<f32, DecodeError> {
+        let angle = f32::from_le_bytes(self.array()?);
+        if !angle.is_finite() {
+            return Err(anyhow!("Angle is not finite").into());
+        }
+        Ok(angle)
+    }
+
+    fn position(&mut self) -> Result<[i32; 3], DecodeError> {
+        let mut pos = [0; 3];
+        for x in &mut pos {
+            *x = i32::from_le_bytes(self.array()?);
+        }
+        Ok(pos)
+    }
+
+    /// Fails for infinite and NaN coordinates
+    fn float_position(&mut self) -> Result<[f64; 3], DecodeError> {
+        let mut pos = [0.0; 3];
+        for x in &mut pos {
+            *x = f64::from_le_bytes(self.array()?);
+        }
+        if !pos.iter().all(|x| x.is_finite()) {
+            return Err(anyhow!("Position is not finite").into());
+        }
+        Ok(pos)
+    }
+
+    /// Fails before reading the string if it is longer than `max_length` bytes
+    fn string(&mut self, max_length: usize) -> Result<String, DecodeError> {
+        let length = self.u16()? as usize;
+        if length > max_length {
+            return Err(anyhow!("String is longer than {max_length} bytes").into());
+        }
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| DecodeError::Invalid(anyhow!(e)))
+    }
+
+    fn block(&mut self) -> Result<Block, DecodeError> {
+        Ok(Block::from_bytes(self.array()?))
+    }
+
+    fn handshake(&mut self) -> Result<Handshake, DecodeError> {
+        let version = self.u32()?;
+        let count = self.u16()? as usize;
+        if count > MAX_CAPABILITIES {
+            return Err(anyhow!("Handshake has more than {MAX_CAPABILITIES} capabilities").into());
+        }
+        let mut capabilities = Vec::new();
+        for _ in 0..count {
+            capabilities.push(self.string(MAX_CAPABILITY_LENGTH)?);
+        }
+        Ok(Handshake {
+            version,
+            capabilities,
+        })
+    }
 }
 
-impl ServerPlayerLogin {
-    pub fn to_arc(&self) -> Arc<[u8]> {
-        assert!(self.name.len() <= u16::MAX as usize);
-        let mut bytes = vec![0u8; 2 + 8 + 2 + self.name.len()];
-        bytes[0..2].copy_from_slice(&3u16.to_le_bytes());
-        bytes[2..4].copy_from_slice(&(self.name.len() as u16).to_le_bytes());
-        bytes[4..4 + self.name.len()].copy_from_slice(self.name.as_bytes());
-        bytes[4 + self.name.len()..].copy_from_slice(self.uid.as_bytes());
-        bytes.into()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_blocks() -> Vec<Block> {
+        (0..CHUNK_VOLUME)
+            .map(|i| Block {
+                id: (i / 100) as u16,
+                state: (i % 3) as u8,
+            })
+            .collect()
+    }
+
+    fn client_bound() -> Vec<ClientBound> {
+        vec![
+            ClientBound::LoginFailure {
+                reason: "Wrong password".to_string(),
+            },
+            ClientBound::LoginSuccess { uid: 7 },
+            ClientBound::PlayerLogin {
+                uid: 3,
+                name: "alice".to_string(),
+            },
+            ClientBound::PlayerLogout { uid: 3 },
+            ClientBound::Handshake(Handshake::new()),
+            ClientBound::Disconnect {
+                reason: "Kicked by the operator".to_string(),
+            },
+            ClientBound::CommandAnswer {
+                answer: "Commands:\nhelp".to_string(),
+            },
+            ClientBound::Chunk {
+                pos: [1, -2, 3],
+                blocks: chunk_blocks(),
+                encoding: ChunkEncoding::Raw,
+            },
+            ClientBound::Chunk {
+                pos: [-1, 2, -3],
+                blocks: chunk_blocks(),
+                encoding: ChunkEncoding::RunLength,
+            },
+            ClientBound::BlockUpdate {
+                pos: [10, -20, 30],
+                block: Block { id: 5, state: 2 },
+            },
+            ClientBound::BlockUpdateRejected {
+                pos: [10, -20, 30],
+                block: Block::AIR,
+            },
+            ClientBound::PlayerPosition {
+                uid: 1,
+                pos: [0.5, 50.0, -3.25],
+                pitch: 0.1,
+                yaw: -1.5,
+            },
+        ]
+    }
+
+    fn server_bound() -> Vec<ServerBound> {
+        vec![
+            ServerBound::Login {
+                name: "alice".to_string(),
+                password: "secret".to_string(),
+            },
+            ServerBound::Handshake(Handshake::new()),
+            ServerBound::Command {
+                line: "kick bob".to_string(),
+            },
+            ServerBound::RequestChunk { pos: [4, -5, 6] },
+            ServerBound::BlockUpdate {
+                pos: [1, 2, 3],
+                block: Block::new(9),
+            },
+            ServerBound::PlayerPosition {
+                pos: [1.0, 2.0, 3.0],
+                pitch: 0.25,
+                yaw: 3.0,
+            },
+        ]
+    }
+
+    /// Decoding the encoding gives the package back, every shorter prefix is incomplete
+    fn assert_round_trip<T: Package + PartialEq + std::fmt::Debug>(package: T) {
+        let bytes = package.encode();
+        match T::decode(&bytes).unwrap() {
+            Decoded::Complete(decoded) => assert_eq!(decoded, package),
+            Decoded::Incomplete(missing) => panic!("{package:?} misses {missing} bytes"),
+        }
+        for end in 0..bytes.len() {
+            match T::decode(&bytes[..end]).unwrap() {
+                Decoded::Incomplete(missing) => assert!(end + missing <= bytes.len()),
+                Decoded::Complete(_) => panic!("{package:?} decoded from {end} bytes"),
+            }
+        }
     }
-    pub async fn new(stream: &mut OwnedReadHalf) -> Self {
-        let mut name_len = [0u8; 2];
-        stream.read_exact(&mut name_len).await.unwrap();
-        let name_len = u16::from_le_bytes(name_len) as usize;
-        let mut name = vec![0u8; name_len];
-        stream.read_exact(&mut name).await.unwrap();
-        let name = String::from_utf8(name).unwrap();
-        let mut uid = [0u8; 8];
-        stream.read_exact(&mut uid).await.unwrap();
-        let uid = u64::from_le_bytes(uid);
-        Self { uid, name }
+
+    #[test]
+    fn client_bound_round_trip() {
+        for package in client_bound() {
+            assert_round_trip(package);
+        }
+    }
+
+    #[test]
+    fn server_bound_round_trip() {
+        for package in server_bound() {
+            assert_round_trip(package);
+        }
+    }
+
+    #[test]
+    fn bytes_after_the_package_are_ignored() {
+        let mut bytes = ServerBound::RequestChunk { pos: [1, 2, 3] }.encode();
+        bytes.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(
+            ServerBound::decode(&bytes).unwrap(),
+            Decoded::Complete(ServerBound::RequestChunk { pos: [1, 2, 3] })
+        );
+    }
+
+    #[test]
+    fn unknown_id_is_rejected() {
+        assert!(ServerBound::decode(&[0xFF, 0x00, 0, 0]).is_err());
+        assert!(ClientBound::decode(&[0x42, 0x42]).is_err());
+    }
+
+    /// The id of a package starting with a string and the length prefix of the string
+    fn string_start(id: u16, length: usize) -> Vec<u8> {
+        let mut bytes = id.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(length as u16).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn oversized_strings_are_rejected_before_they_are_read() {
+        assert!(ServerBound::decode(&string_start(0x0001, MAX_NAME_LENGTH + 1)).is_err());
+        assert!(ServerBound::decode(&string_start(0x0007, MAX_COMMAND_LENGTH + 1)).is_err());
+        assert!(ClientBound::decode(&string_start(0x0001, MAX_REASON_LENGTH + 1)).is_err());
+        assert!(ClientBound::decode(&string_start(0x0006, MAX_REASON_LENGTH + 1)).is_err());
+        assert!(ClientBound::decode(&string_start(0x0007, MAX_ANSWER_LENGTH + 1)).is_err());
+
+        let mut login = string_start(0x0001, 5);
+        login.extend_from_slice(b"alice");
+        login.extend_from_slice(&(MAX_PASSWORD_LENGTH as u16 + 1).to_le_bytes());
+        assert!(ServerBound::decode(&login).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        let mut bytes = string_start(0x0007, 2);
+        bytes.extend_from_slice(&[0xC3, 0x28]);
+        assert!(ServerBound::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn too_many_capabilities_are_rejected() {
+        let mut bytes = 0x0005u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&(MAX_CAPABILITIES as u16 + 1).to_le_bytes());
+        assert!(ServerBound::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn non_finite_positions_are_rejected() {
+        for value in [f64::NAN, f64::INFINITY] {
+            let mut bytes = 0x000Cu16.to_le_bytes().to_vec();
+            for x in [value, 0.0, 0.0] {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            bytes.extend_from_slice(&[0; 8]);
+            assert!(ServerBound::decode(&bytes).is_err());
+        }
+
+        let mut bytes = 0x000Cu16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 24]);
+        bytes.extend_from_slice(&f32::NAN.to_le_bytes());
+        bytes.extend_from_slice(&0f32.to_le_bytes());
+        assert!(ServerBound::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn invalid_run_length_chunk_is_rejected() {
+        let mut bytes = 0x000Du16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 12]);
+        // One run of a single block does not fill the chunk
+        let run = [1u8, 0, 5, 0, 0];
+        bytes.extend_from_slice(&(run.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&run);
+        assert!(ClientBound::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncate_keeps_character_boundaries() {
+        let reason = "ä".repeat(MAX_REASON_LENGTH);
+        let truncated = truncate_reason(reason);
+        assert!(truncated.len() <= MAX_REASON_LENGTH);
+        assert!(truncated.chars().all(|c| c == 'ä'));
+        assert_eq!(truncate_answer("short".to_string()), "short");
     }
 }
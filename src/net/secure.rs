@@ -0,0 +1,498 @@
+//! Optional encryption of the connection with the Noise protocol
+//!
+//! A server started with a key encrypts every connection. The client starts with the first
+//! message of a Noise NX handshake, the server answers with the second one, which contains its
+//! static public key. The client can pin this key, otherwise it trusts any key and prints it.
+//! Afterwards the usual packages are sent in frames of a u16 length followed by the ciphertext.
+//!
+//! A client without encryption gets a login failure package from a server with a key,
+//! a client with encryption reports it if the server answers without.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, bail, ensure};
+use snow::{Builder, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{ClientBound, MAX_REASON_LENGTH, Package};
+
+const NOISE_PARAMS: &str = "Noise_NX_25519_ChaChaPoly_BLAKE2s";
+
+/// Longest frame, limited by the Noise protocol
+const MAX_FRAME: usize = 65535;
+/// Bytes the encryption adds to every frame
+const TAG_SIZE: usize = 16;
+/// Longest plaintext of a frame
+const MAX_PAYLOAD: usize = MAX_FRAME - TAG_SIZE;
+/// Plaintext buffered between the encryption tasks and the package readers and writers
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// Length of the first handshake message, the ephemeral key of the client
+const FIRST_MESSAGE_LENGTH: u16 = 32;
+/// The plain handshake package starts with its id instead of the frame length
+const PLAIN_HANDSHAKE_ID: u16 = 0x0005;
+/// The answer of a server without encryption starts with the login failure id
+const PLAIN_LOGIN_FAILURE_ID: u16 = 0x0001;
+
+/// Length of a public or private key
+pub const KEY_LENGTH: usize = 32;
+
+/// Read half of a connection, decrypted if the connection is encrypted
+pub type Reader = Box<dyn AsyncRead + Unpin + Send>;
+/// Write half of a connection, encrypted if the connection is encrypted
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// How the client connects to the server
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Transport {
+    #[default]
+    Plain,
+    /// Encrypted, fails if the server key is not `pin` when it is given
+    Encrypted { pin: Option<[u8; KEY_LENGTH]> },
+}
+
+/// Static key of a server
+#[derive(Clone)]
+pub struct ServerKey {
+    private: [u8; KEY_LENGTH],
+    pub public: [u8; KEY_LENGTH],
+}
+
+impl ServerKey {
+    /// Loads the key file or creates it with a new key if it does not exist.
+    /// The file contains the private and the public key in hex, one per line.
+    pub fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+            let contents = format!(
+                "{}\n{}\n",
+                to_hex(&keypair.private),
+                to_hex(&keypair.public)
+            );
+            crate::misc::write_atomic_private(path, contents.as_bytes())
+                .with_context(|| format!("Could not write the key file {}", path.display()))?;
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the key file {}", path.display()))?;
+        let mut lines = contents.lines();
+        let (Some(Ok(private)), Some(Ok(public))) =
+            (lines.next().map(from_hex), lines.next().map(from_hex))
+        else {
+            bail!("The key file {} is invalid", path.display());
+        };
+        Ok(ServerKey { private, public })
+    }
+}
+
+/// Lower case hex of a key, as printed by the server and pinned by the client
+pub fn to_hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> anyhow::Result<[u8; KEY_LENGTH]> {
+    let hex = hex.trim();
+    ensure!(
+        hex.len() == KEY_LENGTH * 2 && hex.is_ascii(),
+        "A key has to be {} hex digits",
+        KEY_LENGTH * 2
+    );
+    let mut key = [0; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).context("A key has to be hex")?;
+    }
+    Ok(key)
+}
+
+/// State of an encrypted connection after the handshake
+pub struct Session {
+    transport: Arc<StatelessTransportState>,
+    send_nonce: u64,
+    receive_nonce: u64,
+    /// Decrypted data which was not read yet
+    received: Vec<u8>,
+}
+
+impl Session {
+    fn new(transport: StatelessTransportState) -> Self {
+        Session {
+            transport: Arc::new(transport),
+            send_nonce: 0,
+            receive_nonce: 0,
+            received: Vec::new(),
+        }
+    }
+
+    fn encrypt(&mut self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut frame = vec![0; payload.len() + TAG_SIZE];
+        let len = self
+            .transport
+            .write_message(self.send_nonce, payload, &mut frame)?;
+        frame.truncate(len);
+        self.send_nonce += 1;
+        Ok(frame)
+    }
+
+    fn decrypt(&mut self, frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut payload = vec![0; frame.len()];
+        let len = self
+            .transport
+            .read_message(self.receive_nonce, frame, &mut payload)
+            .context("Could not decrypt a frame")?;
+        payload.truncate(len);
+        self.receive_nonce += 1;
+        Ok(payload)
+    }
+
+    /// Splits the session so that the read and the write half can be used by different tasks
+    fn split(self) -> (Session, Session) {
+        let reader = Session {
+            transport: self.transport.clone(),
+            send_nonce: 0,
+            receive_nonce: self.receive_nonce,
+            received: self.received,
+        };
+        let writer = Session {
+            transport: self.transport,
+            send_nonce: self.send_nonce,
+            receive_nonce: 0,
+            received: Vec::new(),
+        };
+        (reader, writer)
+    }
+}
+
+/// Does the server side of the handshake
+pub async fn accept(stream: TcpStream, key: &ServerKey) -> anyhow::Result<(Reader, Writer)> {
+    let (mut read, mut write) = stream.into_split();
+    let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&key.private)
+        .build_responder()?;
+
+    let len = read.read_u16_le().await?;
+    if len == PLAIN_HANDSHAKE_ID {
+        let reason = "The server only accepts encrypted connections".to_string();
+        // The client is gone if this fails, which is the outcome anyway
+        _ = write
+            .write_all(&ClientBound::LoginFailure { reason }.encode())
+            .await;
+        bail!("Client tried to connect without encryption");
+    }
+    ensure!(
+        len == FIRST_MESSAGE_LENGTH,
+        "Invalid first handshake message"
+    );
+    let mut message = vec![0; len as usize];
+    read.read_exact(&mut message).await?;
+    handshake.read_message(&message, &mut [])?;
+
+    let mut answer = vec![0; MAX_FRAME];
+    let len = handshake.write_message(&[], &mut answer)?;
+    write_frame(&mut write, &answer[..len]).await?;
+
+    let session = Session::new(handshake.into_stateless_transport_mode()?);
+    Ok(wrap(Box::new(read), Box::new(write), session))
+}
+
+/// Wraps the halves of a connection with encryption.
+/// The encryption runs in two tasks, so this has to be called within a tokio runtime.
+pub fn wrap(read: Reader, write: Writer, session: Session) -> (Reader, Writer) {
+    let (reader, writer) = session.split();
+
+    // Each pipe is only used in one direction. Dropping one end closes the other one,
+    // so the tasks and the package readers notice when the other side is gone.
+    let (decrypted_read, decrypted_write) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(decrypt(read, decrypted_write, reader));
+
+    let (plain_read, plain_write) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(encrypt(plain_read, write, writer));
+
+    (Box::new(decrypted_read), Box::new(plain_write))
+}
+
+/// Decrypts frames until the connection or the reader is closed
+async fn decrypt(mut read: Reader, mut decrypted: impl AsyncWrite + Unpin, mut session: Session) {
+    let result: anyhow::Result<()> = async {
+        decrypted.write_all(&session.received).await?;
+        loop {
+            let len = read.read_u16_le().await?;
+            let mut frame = vec![0; len as usize];
+            read.read_exact(&mut frame).await?;
+            let payload = session.decrypt(&frame)?;
+            decrypted.write_all(&payload).await?;
+        }
+    }
+    .await;
+    // Dropping `decrypted` lets the reader of the packages fail, a closed connection is logged there
+    if let Err(e) = result {
+        let closed = e
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof);
+        if !closed {
+            eprintln!("Stopped decrypting the connection: {e}");
+        }
+    }
+}
+
+/// Encrypts everything written to `plain` until it or the connection is closed
+async fn encrypt(mut plain: impl AsyncRead + Unpin, mut write: Writer, mut session: Session) {
+    let mut buffer = vec![0; MAX_PAYLOAD];
+    loop {
+        let len = match plain.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => len,
+        };
+        let frame = match session.encrypt(&buffer[..len]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("Stopped encrypting the connection: {e}");
+                return;
+            }
+        };
+        if write_frame(&mut write, &frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_frame(write: &mut (impl AsyncWrite + Unpin), frame: &[u8]) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(frame.len() + 2);
+    bytes.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(frame);
+    write.write_all(&bytes).await
+}
+
+/// A connection of the client, used blocking during the login
+pub struct Connection {
+    stream: std::net::TcpStream,
+    session: Option<Session>,
+}
+
+impl Connection {
+    /// Connects and does the client side of the handshake if the transport is encrypted
+    pub fn connect(bind: &str, transport: &Transport) -> anyhow::Result<Self> {
+        let stream = std::net::TcpStream::connect(bind)
+            .with_context(|| format!("Could not connect to {bind}"))?;
+        let mut connection = Connection {
+            stream,
+            session: None,
+        };
+        if let Transport::Encrypted { pin } = transport {
+            connection.handshake(pin.as_ref())?;
+        }
+        Ok(connection)
+    }
+
+    fn handshake(&mut self, pin: Option<&[u8; KEY_LENGTH]>) -> anyhow::Result<()> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse()?).build_initiator()?;
+        let mut message = vec![0; MAX_FRAME];
+        let len = handshake.write_message(&[], &mut message)?;
+        write_frame_blocking(&mut self.stream, &message[..len])?;
+
+        let mut len = [0; 2];
+        self.stream.read_exact(&mut len)?;
+        let len = u16::from_le_bytes(len);
+        if len == PLAIN_LOGIN_FAILURE_ID {
+            // The rest of the login failure package
+            let mut reason_len = [0; 2];
+            self.stream.read_exact(&mut reason_len)?;
+            let reason_len = (u16::from_le_bytes(reason_len) as usize).min(MAX_REASON_LENGTH);
+            let mut reason = vec![0; reason_len];
+            self.stream.read_exact(&mut reason)?;
+            bail!(
+                "The server does not support encrypted connections: {}",
+                String::from_utf8_lossy(&reason)
+            );
+        }
+        let mut message = vec![0; len as usize];
+        self.stream.read_exact(&mut message)?;
+        handshake
+            .read_message(&message, &mut [])
+            .context("Invalid answer to the encryption handshake")?;
+
+        let server_key = handshake
+            .get_remote_static()
+            .context("The server did not send its key")?;
+        match pin {
+            Some(pin) => ensure!(
+                server_key == pin,
+                "The server key {} is not the pinned key {}",
+                to_hex(server_key),
+                to_hex(pin)
+            ),
+            None => eprintln!(
+                "Client: The server key is {}, pin it to detect a different server",
+                to_hex(server_key)
+            ),
+        }
+
+        self.session = Some(Session::new(handshake.into_stateless_transport_mode()?));
+        Ok(())
+    }
+
+    /// The async halves of the connection, has to be called within a tokio runtime
+    pub fn into_split(self) -> std::io::Result<(Reader, Writer)> {
+        self.stream.set_nonblocking(true)?;
+        let stream = TcpStream::from_std(self.stream)?;
+        let (read, write) = stream.into_split();
+        Ok(match self.session {
+            Some(session) => wrap(Box::new(read), Box::new(write), session),
+            None => (Box::new(read), Box::new(write)),
+        })
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(session) = &mut self.session else {
+            return self.stream.read(buf);
+        };
+        if session.received.is_empty() {
+            let mut len = [0; 2];
+            self.stream.read_exact(&mut len)?;
+            let mut frame = vec![0; u16::from_le_bytes(len) as usize];
+            self.stream.read_exact(&mut frame)?;
+            session.received = session.decrypt(&frame).map_err(std::io::Error::other)?;
+        }
+        let len = buf.len().min(session.received.len());
+        buf[..len].copy_from_slice(&session.received[..len]);
+        session.received.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(session) = &mut self.session else {
+            return self.stream.write(buf);
+        };
+        let len = buf.len().min(MAX_PAYLOAD);
+        let frame = session
+            .encrypt(&buf[..len])
+            .map_err(std::io::Error::other)?;
+        write_frame_blocking(&mut self.stream, &frame)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn write_frame_blocking(stream: &mut impl Write, frame: &[u8]) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(frame.len() + 2);
+    bytes.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(frame);
+    stream.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::net::{Handshake, ServerBound};
+
+    fn server_key() -> ServerKey {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        ServerKey {
+            private: keypair.private.try_into().unwrap(),
+            public: keypair.public.try_into().unwrap(),
+        }
+    }
+
+    /// Listens on loopback, every accepted connection echoes what it reads
+    async fn echo_server(key: ServerKey) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    if let Ok((mut read, mut write)) = accept(stream, &key).await {
+                        _ = tokio::io::copy(&mut read, &mut write).await;
+                    }
+                });
+            }
+        });
+        bind
+    }
+
+    async fn connect(bind: String, transport: Transport) -> anyhow::Result<Connection> {
+        tokio::task::spawn_blocking(move || Connection::connect(&bind, &transport))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn encrypted_echo_over_loopback() {
+        let key = server_key();
+        let pin = Some(key.public);
+        let bind = echo_server(key).await;
+
+        let connection = connect(bind, Transport::Encrypted { pin }).await.unwrap();
+        let (mut read, mut write) = connection.into_split().unwrap();
+        // More than one frame
+        let sent = (0..3 * MAX_FRAME).map(|i| i as u8).collect::<Vec<_>>();
+        let writing = tokio::spawn(async move {
+            write.write_all(&sent).await.unwrap();
+            sent
+        });
+        let mut received = vec![0; 3 * MAX_FRAME];
+        read.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, writing.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_pinned_key_is_rejected() {
+        let bind = echo_server(server_key()).await;
+        let pin = Some(server_key().public);
+        let error = connect(bind, Transport::Encrypted { pin })
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("is not the pinned key"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_client_gets_a_login_failure() {
+        let bind = echo_server(server_key()).await;
+        let connection = connect(bind, Transport::Plain).await.unwrap();
+        let answer = tokio::task::spawn_blocking(move || {
+            let mut connection = connection;
+            connection.write_all(&ServerBound::Handshake(Handshake::new()).encode())?;
+            ClientBound::read_blocking(&mut connection)
+        });
+        assert!(matches!(
+            answer.await.unwrap().unwrap(),
+            ClientBound::LoginFailure { .. }
+        ));
+    }
+
+    #[test]
+    fn key_file_is_private_and_loaded_again() {
+        let directory = std::env::temp_dir().join(format!("infinirust-key-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("server.key");
+
+        let created = ServerKey::load_or_create(&path).unwrap();
+        let loaded = ServerKey::load_or_create(&path).unwrap();
+        assert_eq!(created.public, loaded.public);
+        assert_eq!(created.private, loaded.private);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Who may join the server
+//!
+//! The whitelist and the bans are stored in whitelist.json and bans.json in the world directory.
+//! They are written whenever the operator changes them and checked on every login.
+//! Online players who are no longer allowed to join after a change are kicked.
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Whitelist {
+    /// Only names in the list can log in if it is enabled
+    enabled: bool,
+    names: BTreeSet<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Bans {
+    names: BTreeSet<String>,
+    addresses: BTreeSet<IpAddr>,
+}
+
+/// Commands of the operator for the lists
+#[derive(Debug, Clone)]
+pub enum AccessCommand {
+    WhitelistAdd(String),
+    WhitelistRemove(String),
+    WhitelistList,
+    /// Turns the whitelist on or off
+    WhitelistEnable(bool),
+    Ban(String),
+    Pardon(String),
+    BanAddress(IpAddr),
+    /// Bans the address of the online player with the name, it has to be resolved to
+    /// [`AccessCommand::BanAddress`] before it is applied
+    BanAddressOf(String),
+    PardonAddress(IpAddr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Access {
+    world_directory: PathBuf,
+    whitelist: Whitelist,
+    bans: Bans,
+}
+
+impl Access {
+    /// Missing files are empty lists
+    pub fn load(world_directory: &Path) -> anyhow::Result<Self> {
+        Ok(Access {
+            world_directory: world_directory.to_path_buf(),
+            whitelist: load_json(&world_directory.join("whitelist.json"))?,
+            bans: load_json(&world_directory.join("bans.json"))?,
+        })
+    }
+
+    /// Fails with the reason for the client
+    pub fn check(&self, name: &str, address: IpAddr) -> Result<(), String> {
+        if self.bans.names.contains(name) || self.bans.addresses.contains(&address) {
+            return Err("You are banned from this server".to_string());
+        }
+        if self.whitelist.enabled && !self.whitelist.names.contains(name) {
+            return Err("You are not on the whitelist of this server".to_string());
+        }
+        Ok(())
+    }
+
+    /// Applies a change and saves the lists, returns the answer for the operator.
+    /// If the lists can not be saved nothing changes.
+    pub fn apply(&mut self, command: AccessCommand) -> anyhow::Result<String> {
+        let mut changed = self.clone();
+        let answer = match command {
+            AccessCommand::WhitelistAdd(name) => {
+                let answer = format!("Added {name} to the whitelist");
+                changed.whitelist.names.insert(name);
+                answer
+            }
+            AccessCommand::WhitelistRemove(name) => {
+                changed.whitelist.names.remove(&name);
+                format!("Removed {name} from the whitelist")
+            }
+            AccessCommand::WhitelistList => {
+                let names = self.whitelist.names.iter().cloned().collect::<Vec<_>>();
+                let state = if self.whitelist.enabled { "on" } else { "off" };
+                return Ok(format!("Whitelist ({state}): {}", names.join(", ")));
+            }
+            AccessCommand::WhitelistEnable(enabled) => {
+                changed.whitelist.enabled = enabled;
+                let state = if enabled { "on" } else { "off" };
+                format!("Whitelist is {state}")
+            }
+            AccessCommand::Ban(name) => {
+                let answer = format!("Banned {name}");
+                changed.bans.names.insert(name);
+                answer
+            }
+            AccessCommand::Pardon(name) => {
+                changed.bans.names.remove(&name);
+                format!("Pardoned {name}")
+            }
+            AccessCommand::BanAddress(address) => {
+                changed.bans.addresses.insert(address);
+                format!("Banned {address}")
+            }
+            AccessCommand::PardonAddress(address) => {
+                changed.bans.addresses.remove(&address);
+                format!("Pardoned {address}")
+            }
+            AccessCommand::BanAddressOf(name) => bail!("{name} is not online"),
+        };
+        changed.save()?;
+        *self = changed;
+        Ok(answer)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        save_json(
+            &self.world_directory.join("whitelist.json"),
+            &self.whitelist,
+        )?;
+        save_json(&self.world_directory.join("bans.json"), &self.bans)
+    }
+}
+
+fn load_json<T: Default + for<'a> Deserialize<'a>>(path: &Path) -> anyhow::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .with_context(|| format!("Could not parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).with_context(|| format!("Could not read {}", path.display())),
+    }
+}
+
+fn save_json(path: &Path, value: &impl Serialize) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+    crate::misc::write_atomic(path, json.as_bytes())
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_world(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("infinirust-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn changes_are_saved_and_loaded_again() {
+        let world = temp_world("access-saved");
+        let mut access = Access::load(&world).unwrap();
+        let address = "10.0.0.1".parse().unwrap();
+        access.apply(AccessCommand::Ban("mallory".into())).unwrap();
+        access.apply(AccessCommand::BanAddress(address)).unwrap();
+        access.apply(AccessCommand::WhitelistEnable(true)).unwrap();
+        access
+            .apply(AccessCommand::WhitelistAdd("alice".into()))
+            .unwrap();
+
+        let access = Access::load(&world).unwrap();
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert!(access.check("alice", localhost).is_ok());
+        assert!(access.check("alice", address).is_err());
+        assert!(access.check("mallory", localhost).is_err());
+        assert!(access.check("bob", localhost).is_err());
+        std::fs::remove_dir_all(world).unwrap();
+    }
+
+    #[test]
+    fn failed_save_changes_nothing() {
+        let world = temp_world("access-failed");
+        let mut access = Access::load(&world).unwrap();
+        // The lists can not be written into a directory which is a file
+        access.world_directory = world.join("file");
+        std::fs::write(&access.world_directory, b"").unwrap();
+
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert!(access.apply(AccessCommand::Ban("alice".into())).is_err());
+        assert!(access.check("alice", localhost).is_ok());
+        assert!(access.apply(AccessCommand::WhitelistEnable(true)).is_err());
+        assert!(access.check("alice", localhost).is_ok());
+        std::fs::remove_dir_all(world).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Passwords of the players
+//!
+//! Every registered player has a password, which is stored as a salted argon2 hash in
+//! players.json. Who may register a new name is configured with `registration` in settings.json.
+//! Players from before passwords existed can only log in after the operator set their password
+//! with `register`, except on the internal server of a local game.
+//! Every address only gets a few password checks, see [`LoginAttempts`].
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use super::rules::RateLimit;
+
+/// Password checks an address can make at once
+const LOGIN_ATTEMPT_BURST: f64 = 5.0;
+
+/// Password checks per second an address gets back after the burst
+const LOGIN_ATTEMPTS_PER_SECOND: f64 = 0.1;
+
+/// More tracked addresses than this drop the ones which have their whole burst again
+const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// Who may register a new name, configured in settings.json
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Registration {
+    /// The first login with a new name registers it with the given password
+    #[default]
+    Open,
+    /// Only the operator registers names with the `register` command
+    Closed,
+}
+
+/// What the server knows about a name before it is logged in
+#[derive(Debug, Clone, PartialEq)]
+pub enum Account {
+    /// The name is free and the login registers it
+    Free,
+    /// The name is free, but registration is closed
+    Closed,
+    /// Registered before passwords existed. Only the internal server lets the login set the
+    /// password, otherwise the operator has to set it with `register`.
+    NoPassword,
+    /// The hash of the password
+    Password(String),
+}
+
+/// Proof of the password checked by the connection before the login
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    /// The password matched this hash
+    Verified(String),
+    /// New hash for a name which has no password yet
+    Register(String),
+}
+
+/// Salted hash in the PHC string format, slow on purpose.
+/// Do not call it in the world thread.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 with default parameters can hash every password")
+        .to_string()
+}
+
+/// False if the password does not match or the hash is invalid
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Limits the password checks per address, every check costs an argon2 hash and a wrong
+/// password can be tried again
+#[derive(Debug, Default)]
+pub struct LoginAttempts {
+    limits: Mutex<HashMap<IpAddr, RateLimit>>,
+}
+
+impl LoginAttempts {
+    /// Uses up one password check of the address, false if it has to wait
+    pub fn try_attempt(&self, address: IpAddr) -> bool {
+        let mut limits = self.limits.lock().unwrap();
+        if limits.len() >= MAX_TRACKED_ADDRESSES {
+            limits.retain(|_, limit| !limit.is_full());
+        }
+        limits
+            .entry(address)
+            .or_insert_with(|| RateLimit::new(LOGIN_ATTEMPTS_PER_SECOND, LOGIN_ATTEMPT_BURST))
+            .try_acquire()
+    }
+}
@@ -0,0 +1,108 @@
+//! Biomes decide how the terrain of a column looks like
+
+use crate::game::BlockType;
+
+use super::world_generator::{DIRT, GRASS, SAND, SNOW, STONE};
+
+/// Surface blocks of mountains above this height are snow
+const SNOW_LINE: i32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Biome {
+    Plains,
+    Desert,
+    Mountains,
+    Forest,
+}
+
+impl Biome {
+    pub const ALL: [Biome; 4] = [
+        Biome::Plains,
+        Biome::Desert,
+        Biome::Mountains,
+        Biome::Forest,
+    ];
+
+    /// Position of the biome in the (temperature, humidity) plane.
+    /// A column gets the biome with the closest center.
+    fn climate_center(self) -> (f64, f64) {
+        match self {
+            Biome::Plains => (0.1, -0.1),
+            Biome::Desert => (0.5, -0.5),
+            Biome::Mountains => (-0.45, 0.0),
+            Biome::Forest => (0.0, 0.4),
+        }
+    }
+
+    fn climate_distance_squared(self, temperature: f64, humidity: f64) -> f64 {
+        let (t, h) = self.climate_center();
+        (temperature - t).powi(2) + (humidity - h).powi(2)
+    }
+
+    pub fn from_climate(temperature: f64, humidity: f64) -> Self {
+        Self::ALL
+            .into_iter()
+            .min_by(|a, b| {
+                a.climate_distance_squared(temperature, humidity)
+                    .total_cmp(&b.climate_distance_squared(temperature, humidity))
+            })
+            .unwrap()
+    }
+
+    /// How much each biome contributes to the terrain shape at this climate.
+    /// The weights change smoothly, so there are no cliffs at biome borders.
+    pub fn weights(temperature: f64, humidity: f64) -> [f64; 4] {
+        Self::ALL.map(|biome| {
+            1.0 / (biome.climate_distance_squared(temperature, humidity) + 0.01).powi(2)
+        })
+    }
+
+    /// Factor for the height noise
+    pub fn height_amplitude(self) -> f64 {
+        match self {
+            Biome::Plains => 0.6,
+            Biome::Desert => 0.35,
+            Biome::Mountains => 2.0,
+            Biome::Forest => 0.8,
+        }
+    }
+
+    /// Added to the terrain height
+    pub fn height_offset(self) -> f64 {
+        match self {
+            Biome::Plains => 0.0,
+            Biome::Desert => 2.0,
+            Biome::Mountains => 8.0,
+            Biome::Forest => 2.0,
+        }
+    }
+
+    /// Top block of a column with its surface at `height`
+    pub fn surface_block(self, height: i32) -> BlockType {
+        match self {
+            Biome::Plains | Biome::Forest => GRASS,
+            Biome::Desert => SAND,
+            Biome::Mountains if height >= SNOW_LINE => SNOW,
+            Biome::Mountains => STONE,
+        }
+    }
+
+    /// The blocks between the surface and the stone
+    pub fn filler_block(self) -> BlockType {
+        match self {
+            Biome::Plains | Biome::Forest => DIRT,
+            Biome::Desert => SAND,
+            Biome::Mountains => STONE,
+        }
+    }
+
+    /// Probability that a tree grows on a column of this biome, trees only grow on grass
+    pub fn tree_density(self) -> f64 {
+        match self {
+            Biome::Plains => 0.002,
+            Biome::Desert => 0.0,
+            Biome::Mountains => 0.0,
+            Biome::Forest => 0.03,
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Admin commands, which are run from stdin and by players with the permission
+//!
+//! A command is a line of words like `kick alice griefing`, it is parsed and run in the world
+//! thread. Every command needs a [`Permission`] level. Stdin has all of them, players get theirs
+//! from the operator with the `permission` command, it is stored in players.json.
+//! Players can not run commands against players with the same or a higher permission.
+//! The answer is printed for stdin and sent to players with the 0x0007 package.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::access::AccessCommand;
+use super::player::is_valid_name;
+use crate::net::MAX_PASSWORD_LENGTH;
+
+/// What a player may do on the server, the levels include everything below them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    #[default]
+    Player,
+    /// Kicks and bans players and changes the whitelist
+    Moderator,
+    /// Runs every command
+    Operator,
+}
+
+impl Permission {
+    const ALL: [Permission; 3] = [
+        Permission::Player,
+        Permission::Moderator,
+        Permission::Operator,
+    ];
+
+    /// The name used in commands and players.json
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Player => "player",
+            Permission::Moderator => "moderator",
+            Permission::Operator => "operator",
+        }
+    }
+
+    fn parse(level: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == level)
+    }
+}
+
+/// Every command with its usage and the permission it needs, in the order `help` lists them
+const COMMANDS: &[(&str, Permission)] = &[
+    ("help", Permission::Player),
+    ("kick <name> [reason]", Permission::Moderator),
+    ("ban <name>", Permission::Moderator),
+    ("pardon <name>", Permission::Moderator),
+    (
+        "whitelist add <name> | remove <name> | list | on | off",
+        Permission::Moderator,
+    ),
+    ("ban-ip <address|name>", Permission::Operator),
+    ("pardon-ip <address>", Permission::Operator),
+    ("register <name> <password>", Permission::Operator),
+    (
+        "permission <name> <player|moderator|operator>",
+        Permission::Operator,
+    ),
+    (
+        "pregen <x1> <z1> <x2> <z2> [<y1> <y2>]",
+        Permission::Operator,
+    ),
+    ("exit", Permission::Operator),
+];
+
+#[derive(Debug, Clone)]
+pub enum AdminCommand {
+    /// Lists the commands the sender may run
+    Help,
+    /// Closes the connection of the player with the name, the reason is shown to the player
+    Kick(String, Option<String>),
+    /// Changes the whitelist or the bans
+    Access(AccessCommand),
+    /// Registers the name or replaces its password, the password is not hashed yet
+    Register(String, String),
+    SetPermission(String, Permission),
+    /// Generates and saves all chunks between the two chunk positions (inclusive)
+    Pregenerate([i32; 3], [i32; 3]),
+    Shutdown,
+}
+
+impl AdminCommand {
+    /// Fails with the answer for the sender, e.g. the usage of the command
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let access = |command| Ok(AdminCommand::Access(command));
+        match words.as_slice() {
+            ["help"] => Ok(AdminCommand::Help),
+            ["kick", name] => Ok(AdminCommand::Kick(name.to_string(), None)),
+            ["kick", name, reason @ ..] => {
+                Ok(AdminCommand::Kick(name.to_string(), Some(reason.join(" "))))
+            }
+            ["ban", name] => access(AccessCommand::Ban(name.to_string())),
+            ["pardon", name] => access(AccessCommand::Pardon(name.to_string())),
+            // The address of an online player can be banned by its name
+            ["ban-ip", target] => access(match target.parse::<IpAddr>() {
+                Ok(address) => AccessCommand::BanAddress(address),
+                Err(_) => AccessCommand::BanAddressOf(target.to_string()),
+            }),
+            ["pardon-ip", address] => match address.parse::<IpAddr>() {
+                Ok(address) => access(AccessCommand::PardonAddress(address)),
+                Err(_) => Err(usage("pardon-ip")),
+            },
+            ["whitelist", "add", name] => access(AccessCommand::WhitelistAdd(name.to_string())),
+            ["whitelist", "remove", name] => {
+                access(AccessCommand::WhitelistRemove(name.to_string()))
+            }
+            ["whitelist", "list"] => access(AccessCommand::WhitelistList),
+            ["whitelist", "on"] => access(AccessCommand::WhitelistEnable(true)),
+            ["whitelist", "off"] => access(AccessCommand::WhitelistEnable(false)),
+            ["register", name, password] => {
+                if is_valid_name(name) && password.len() <= MAX_PASSWORD_LENGTH {
+                    Ok(AdminCommand::Register(
+                        name.to_string(),
+                        password.to_string(),
+                    ))
+                } else {
+                    Err("Invalid name or password".to_string())
+                }
+            }
+            ["permission", name, level] => match Permission::parse(level) {
+                Some(level) => Ok(AdminCommand::SetPermission(name.to_string(), level)),
+                None => Err(usage("permission")),
+            },
+            ["pregen", coordinates @ ..] => match parse_pregen(coordinates) {
+                Some((from, to)) => Ok(AdminCommand::Pregenerate(from, to)),
+                None => Err(usage("pregen")),
+            },
+            ["exit"] => Ok(AdminCommand::Shutdown),
+            [command, ..] => Err(usage(command)),
+            [] => Err(usage("")),
+        }
+    }
+
+    pub fn permission(&self) -> Permission {
+        match self {
+            AdminCommand::Help => Permission::Player,
+            // Addresses are only seen by operators
+            AdminCommand::Access(
+                AccessCommand::BanAddress(_)
+                | AccessCommand::BanAddressOf(_)
+                | AccessCommand::PardonAddress(_),
+            ) => Permission::Operator,
+            AdminCommand::Kick(..) | AdminCommand::Access(_) => Permission::Moderator,
+            AdminCommand::Register(..)
+            | AdminCommand::SetPermission(..)
+            | AdminCommand::Pregenerate(..)
+            | AdminCommand::Shutdown => Permission::Operator,
+        }
+    }
+}
+
+/// Who a command acts against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target<'a> {
+    Name(&'a str),
+    /// All online players with the address
+    Address(IpAddr),
+}
+
+impl AdminCommand {
+    /// The player the command acts against, the sender needs a higher permission than them
+    pub fn target(&self) -> Option<Target<'_>> {
+        match self {
+            AdminCommand::Kick(name, _)
+            | AdminCommand::Register(name, _)
+            | AdminCommand::SetPermission(name, _)
+            | AdminCommand::Access(
+                AccessCommand::Ban(name)
+                | AccessCommand::BanAddressOf(name)
+                | AccessCommand::WhitelistRemove(name),
+            ) => Some(Target::Name(name)),
+            AdminCommand::Access(AccessCommand::BanAddress(address)) => {
+                Some(Target::Address(*address))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The usages of all commands the permission allows, one per line
+pub fn help(permission: Permission) -> String {
+    let usages = COMMANDS
+        .iter()
+        .filter(|(_, needed)| *needed <= permission)
+        .map(|(usage, _)| *usage)
+        .collect::<Vec<_>>();
+    format!("Commands:\n{}", usages.join("\n"))
+}
+
+/// The answer for wrong arguments of the command
+fn usage(command: &str) -> String {
+    match COMMANDS
+        .iter()
+        .find(|(usage, _)| usage.split(' ').next() == Some(command))
+    {
+        Some((usage, _)) => format!("Usage: {usage}"),
+        None => "Unknown command, see help".to_string(),
+    }
+}
+
+/// Block height range which is pregenerated if none is given
+const PREGEN_DEFAULT_Y: [i32; 2] = [-64, 127];
+
+/// Parses the block coordinates of the `pregen` command into the corners of the chunk area
+fn parse_pregen(coordinates: &[&str]) -> Option<([i32; 3], [i32; 3])> {
+    let coordinates = coordinates
+        .iter()
+        .map(|c| c.parse::<i32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (x, z, y) = match coordinates.as_slice() {
+        [x1, z1, x2, z2] => ([*x1, *x2], [*z1, *z2], PREGEN_DEFAULT_Y),
+        [x1, z1, x2, z2, y1, y2] => ([*x1, *x2], [*z1, *z2], [*y1, *y2]),
+        _ => return None,
+    };
+
+    let chunk = |block: i32| block.div_euclid(crate::game::CHUNK_SIZE as i32);
+    let from = [x, y, z].map(|[a, b]| chunk(a.min(b)));
+    let to = [x, y, z].map(|[a, b]| chunk(a.max(b)));
+    Some((from, to))
+}
@@ -0,0 +1,118 @@
+//! Features are structures like trees which are placed on top of the generated terrain.
+//!
+//! A feature can reach into neighbouring chunks. Every chunk therefore looks at all feature
+//! origins within `horizontal_extent` of its border and only writes the blocks inside itself.
+//! Origins and shapes are a pure function of the seed and the position, so a feature
+//! looks the same no matter in which order the chunks are generated.
+
+use crate::game::{BlockType, ChunkIndex};
+
+use super::biome::Biome;
+use super::world::ChunkData;
+use super::world_generator::{AIR, GRASS, LEAVES, LOG};
+
+const CHUNK_SIZE: i32 = crate::game::CHUNK_SIZE as i32;
+
+/// Restricts the writes of a feature to one chunk
+pub struct ChunkWriter<'a> {
+    chunk: &'a mut ChunkData,
+    /// Block position of the chunk corner with the lowest coordinates
+    origin: [i32; 3],
+}
+
+impl<'a> ChunkWriter<'a> {
+    pub fn new(chunk: &'a mut ChunkData, pos: &ChunkIndex) -> Self {
+        ChunkWriter {
+            chunk,
+            origin: pos.map(|x| x * CHUNK_SIZE),
+        }
+    }
+
+    fn local(&self, pos: [i32; 3]) -> Option<[usize; 3]> {
+        let mut local = [0; 3];
+        for i in 0..3 {
+            let x = pos[i] - self.origin[i];
+            if !(0..CHUNK_SIZE).contains(&x) {
+                return None;
+            }
+            local[i] = x as usize;
+        }
+        Some(local)
+    }
+
+    /// Sets the block at the world position `pos` if it is inside the chunk and `replace`
+    /// returns true for the block which is there
+    pub fn set_if(&mut self, pos: [i32; 3], block: BlockType, replace: impl Fn(BlockType) -> bool) {
+        if let Some(local) = self.local(pos)
+            && replace(self.chunk.get(local.map(|x| x as u8)))
+        {
+            self.chunk.set(local, block);
+        }
+    }
+
+    /// Lowest and highest block y coordinate of the chunk
+    pub fn y_range(&self) -> std::ops::Range<i32> {
+        self.origin[1]..self.origin[1] + CHUNK_SIZE
+    }
+}
+
+pub trait Feature: Send + Sync {
+    /// Maximum horizontal distance of a placed block from the origin
+    fn horizontal_extent(&self) -> i32;
+    /// Maximum height of a placed block above the origin
+    fn height(&self) -> i32;
+    /// Probability that the feature is placed on a column of this biome
+    fn density(&self, biome: Biome) -> f64;
+    /// Whether the feature can stand on this surface block
+    fn allowed_on(&self, surface: BlockType) -> bool;
+    /// Places the feature with its lowest block at `origin`.
+    /// `random` is a deterministic random number for this origin.
+    fn place(&self, origin: [i32; 3], random: u64, writer: &mut ChunkWriter);
+}
+
+/// A log with a crown of leaves
+pub struct Tree;
+
+impl Feature for Tree {
+    fn horizontal_extent(&self) -> i32 {
+        2
+    }
+
+    fn height(&self) -> i32 {
+        8
+    }
+
+    fn density(&self, biome: Biome) -> f64 {
+        biome.tree_density()
+    }
+
+    fn allowed_on(&self, surface: BlockType) -> bool {
+        surface == GRASS
+    }
+
+    fn place(&self, origin: [i32; 3], random: u64, writer: &mut ChunkWriter) {
+        let [x, y, z] = origin;
+        let trunk_height = 4 + (random % 3) as i32;
+        let top = y + trunk_height;
+
+        for dy in 0..trunk_height {
+            writer.set_if([x, y + dy, z], LOG, |b| b == AIR || b == LEAVES);
+        }
+
+        for ly in top - 2..=top + 1 {
+            let radius: i32 = if ly < top { 2 } else { 1 };
+            for dx in -radius..=radius {
+                for dz in -radius..=radius {
+                    // Leave out the corners for a rounder crown
+                    if radius > 1 && dx.abs() == radius && dz.abs() == radius {
+                        continue;
+                    }
+                    if ly == top + 1 && dx != 0 && dz != 0 {
+                        continue;
+                    }
+                    writer.set_if([x + dx, ly, z + dz], LEAVES, |b| b == AIR);
+                }
+            }
+        }
+    }
+}
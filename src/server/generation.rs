@@ -0,0 +1,61 @@
+//! Chunk generation on a pool of worker threads
+//!
+//! The world loop hands out the positions of chunks which are neither loaded nor stored on disk.
+//! The workers generate them and send the result back as [`Command::ChunkGenerated`],
+//! so the world loop keeps handling other commands in the meantime.
+
+use std::sync::{Arc, Mutex, mpsc};
+
+use crate::game::ChunkIndex;
+
+use super::world_generator::WorldGenerator;
+use super::{Command, NOUSER, ServerCommand};
+
+pub struct GenerationPool {
+    jobs: mpsc::Sender<ChunkIndex>,
+}
+
+impl GenerationPool {
+    /// Starts `threads` workers, 0 starts one worker per CPU core
+    pub fn new(generator: Arc<dyn WorldGenerator>, threads: usize, results: ServerCommand) -> Self {
+        let threads = if threads == 0 {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        };
+
+        let (jobs, jobs_rx) = mpsc::channel::<ChunkIndex>();
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+
+        for _ in 0..threads {
+            let generator = generator.clone();
+            let jobs_rx = jobs_rx.clone();
+            let results = results.clone();
+            std::thread::spawn(move || {
+                loop {
+                    // The lock is released before the chunk is generated
+                    let Ok(pos) = jobs_rx.lock().unwrap().recv() else {
+                        return;
+                    };
+                    let chunk = generator.generate(&pos);
+                    // The world loop is gone when sending fails
+                    if results
+                        .blocking_send((NOUSER, Command::ChunkGenerated(pos, Box::new(chunk))))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+
+        GenerationPool { jobs }
+    }
+
+    /// Queues the chunk for generation, the result arrives as [`Command::ChunkGenerated`]
+    pub fn generate(&self, pos: ChunkIndex) {
+        self.jobs
+            .send(pos)
+            .expect("Server: generation workers are not running");
+    }
+}
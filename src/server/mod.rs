@@ -1,14 +1,23 @@
 use std::sync::Arc;
 
-use zerocopy::IntoBytes;
+use crate::game::Block;
+use crate::net::{ClientBound, truncate_answer};
 
-use crate::net::{ServerPackageLogout, ServerPackagePlayerPosition, ServerPlayerLogin};
-
-use self::player::Players;
-use self::world::ServerWorld;
+use self::auth::Account;
+use self::commands::{AdminCommand, Permission, Target};
+use self::player::{LoginRequest, Players};
+use self::world::{ChunkData, ServerWorld};
 use crate::net::Package;
 
+pub mod access;
+pub mod auth;
+pub mod biome;
+pub mod commands;
+pub mod features;
+pub mod generation;
 pub mod player;
+pub mod region;
+pub mod rules;
 pub mod stdin;
 pub mod world;
 pub mod world_generator;
@@ -27,66 +36,97 @@ pub enum BlockUpdateMode {
 #[derive(Debug)]
 pub enum Command {
     ChunkData([i32; 3]),
-    Login(String, Client, tokio::sync::oneshot::Sender<Option<UID>>),
+    /// Sent by the generation workers when a chunk is ready
+    ChunkGenerated([i32; 3], Box<ChunkData>),
+    /// What the login of the name has to check
+    Account(String, tokio::sync::oneshot::Sender<Account>),
+    /// The channel gets the uid or the reason why the login failed
+    Login(
+        LoginRequest,
+        tokio::sync::oneshot::Sender<Result<UID, String>>,
+    ),
     Logout,
-    BlockUpdate([i32; 3], u8),
+    /// A line of the admin commands, from stdin or the player
+    Run(String),
+    /// Registers the name or replaces its password with the hash.
+    /// Sent back by the thread hashing the password of `register` with the uid of the sender.
+    SetPassword(String, String),
+    BlockUpdate([i32; 3], Block),
     PlayerPosition([f64; 3], f32, f32),
+    Autosave,
+    /// Sent by the autosave thread when it is done, false if writing the chunks failed
+    Autosaved(bool),
     Shutdown,
 }
 
 /// Supposed to be started in a new tread
+/// `commands` is the sending side of `input`, it is used to schedule autosaves
 pub fn start_world(
     mut input: tokio::sync::mpsc::Receiver<(UID, Command)>,
+    commands: ServerCommand,
     world_directory: std::path::PathBuf,
 ) {
-    let mut server = Server::new(&world_directory);
+    let mut server = Server::new(&world_directory, commands.clone()).unwrap_or_else(|e| {
+        eprintln!("Server: Could not load world: {e:#}");
+        std::process::exit(1);
+    });
+
+    if let Some(interval) = server.world.autosave_interval() {
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                // The world loop is gone when sending fails
+                if commands.blocking_send((NOUSER, Command::Autosave)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
 
     while let Some((uid, command)) = input.blocking_recv() {
         match command {
-            Command::Login(name, client, back) => {
-                let uid = server.players.login(name, client);
-                back.send(uid).expect("Server: Could not send uid back");
-                if let Some(uid) = uid {
-                    //send login success package
-                    let mut package =
-                        vec![0x02u8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-                    package[2..].copy_from_slice((uid as u64).as_bytes());
-                    server
-                        .players
-                        .client(uid)
-                        .try_send(Arc::from(package))
-                        .unwrap();
+            Command::Account(name, back) => {
+                // The connection is gone if the receiver was dropped
+                _ = back.send(server.players.account(&name));
+            }
+            Command::Login(request, back) => {
+                let uid = server.players.login(request);
+                let accepted = uid.as_ref().ok().copied();
+                if back.send(uid).is_err() {
+                    // The login timed out, nobody reads the connection of the player
+                    if let Some(uid) = accepted {
+                        server.players.logout(uid);
+                    }
+                    continue;
+                }
+                if let Some(uid) = accepted {
+                    // Sending fails if the client already disconnected, its reader logs it out then
+                    //send login success package with the uid
+                    let package = ClientBound::LoginSuccess { uid: uid as u64 };
+                    _ = server.players.client(uid).try_send(package.to_arc());
                     //Send position package to the new player
                     let player = server.players.get_player_mut(uid);
-                    let package = ServerPackagePlayerPosition {
+                    let package = ClientBound::PlayerPosition {
                         uid: uid as u64,
                         pos: player.pos,
                         pitch: player.pitch,
                         yaw: player.yaw,
                     };
-                    server
-                        .players
-                        .client(uid)
-                        .try_send(package.to_arc())
-                        .unwrap();
+                    _ = server.players.client(uid).try_send(package.to_arc());
 
                     //Send login package of all online players
                     for player in server.players.online() {
                         if player.uid != uid {
-                            let package = ServerPlayerLogin {
+                            let package = ClientBound::PlayerLogin {
                                 uid: player.uid as u64,
                                 name: player.player.name.clone(),
                             };
-                            server
-                                .players
-                                .client(uid)
-                                .try_send(package.to_arc())
-                                .unwrap();
+                            _ = server.players.client(uid).try_send(package.to_arc());
                         }
                     }
 
                     //Send all the other players that this on logged in
-                    let package = ServerPlayerLogin {
+                    let package = ClientBound::PlayerLogin {
                         uid: uid as u64,
                         name: server.players.get_player_mut(uid).name.clone(),
                     };
@@ -97,25 +137,37 @@ pub fn start_world(
             }
             Command::Logout => {
                 server.players.logout(uid);
-                server
-                    .players
-                    .broadcast_filtered(ServerPackageLogout { uid: uid as u64 }.to_arc(), |p| {
-                        p.uid != uid
-                    });
+                server.players.broadcast_filtered(
+                    ClientBound::PlayerLogout { uid: uid as u64 }.to_arc(),
+                    |p| p.uid != uid,
+                );
+            }
+            Command::Run(line) => server.run(uid, &line),
+            Command::SetPassword(name, hash) => {
+                server.players.set_password(name.clone(), hash);
+                server.answer(uid, format!("Set the password of {name}"));
             }
             Command::ChunkData(pos) => {
-                // If the buffer is full or client disconnect, this package will not be send
-                _ = server
-                    .players
-                    .client(uid)
-                    .try_send(server.world.get_chunk_data(&pos));
+                server.send_chunk(uid, &pos);
+                server.unload_chunks();
+            }
+            Command::ChunkGenerated(pos, chunk) => {
+                let waiting = server.world.finish_generation(&pos, *chunk);
+                for uid in waiting {
+                    // The player might have logged out while the chunk was generated
+                    if server.players.is_online(uid) {
+                        // The chunk is loaded now, so the package is sent right away
+                        server.send_chunk(uid, &pos);
+                    }
+                }
+                server.unload_chunks();
             }
             Command::PlayerPosition(pos, pitch, yaw) => {
                 let player = server.players.get_player_mut(uid);
                 player.pos = pos;
                 player.pitch = pitch;
                 player.yaw = yaw;
-                let package = ServerPackagePlayerPosition {
+                let package = ClientBound::PlayerPosition {
                     uid: uid as u64,
                     pos,
                     pitch,
@@ -127,15 +179,32 @@ pub fn start_world(
                     .broadcast_filtered(package.to_arc(), |p| p.uid != uid);
             }
             Command::BlockUpdate(pos, block) => {
-                let package = server.world.process_block_update(&pos, block);
-                server.players.broadcast(package);
+                let accepted = server.allow_block_update(uid, &pos, block)
+                    && server.world.process_block_update(&pos, block);
+                if accepted {
+                    server
+                        .players
+                        .broadcast(ClientBound::BlockUpdate { pos, block }.to_arc());
+                } else if let Some(block) = server.world.get_block(&pos) {
+                    // Reverts the block on the client, which changed it already
+                    let package = ClientBound::BlockUpdateRejected { pos, block };
+                    _ = server.players.client(uid).try_send(package.to_arc());
+                } else {
+                    // The chunk is not loaded, the whole chunk is sent again as the revert.
+                    // This loads it, so the next update of the player applies.
+                    let (chunk_pos, _) = crate::game::chunk::block_position_to_chunk_index(pos);
+                    server.send_chunk(uid, &chunk_pos);
+                }
             }
-            Command::Shutdown => {
-                server.players.sync_to_disk(&world_directory).unwrap();
-                server.world.sync_to_disk(&world_directory).unwrap();
-                eprintln!("Server: shut down after saving to disk");
-                std::process::exit(0);
+            Command::Autosave => {
+                if let Err(e) = server.players.sync_to_disk(&world_directory) {
+                    eprintln!("Server: autosave of players failed: {e}");
+                }
+                // Writing the chunks is slow, it is done in the background
+                server.world.start_autosave(server.commands.clone());
             }
+            Command::Autosaved(success) => server.world.finish_autosave(success),
+            Command::Shutdown => server.shutdown(),
         }
     }
 }
@@ -143,14 +212,165 @@ pub fn start_world(
 struct Server {
     world: ServerWorld,
     players: Players,
+    world_directory: std::path::PathBuf,
+    /// Used to send the hashed passwords of `register` back to the world
+    commands: ServerCommand,
 }
 
 impl Server {
-    fn new(world_directory: &std::path::Path) -> Self {
-        let players = Players::new(world_directory);
+    fn new(world_directory: &std::path::Path, commands: ServerCommand) -> anyhow::Result<Self> {
+        let world = ServerWorld::from_files(world_directory, commands.clone())?;
 
-        let world = ServerWorld::from_files(world_directory);
+        let players = Players::new(world_directory, world.registration());
 
-        Server { world, players }
+        Ok(Server {
+            world,
+            players,
+            world_directory: world_directory.to_path_buf(),
+            commands,
+        })
+    }
+
+    /// Sends the chunk to the player, or once it is generated.
+    /// A chunk which can not be read is not sent, the client asks again when its camera moves
+    /// into another chunk.
+    fn send_chunk(&mut self, uid: UID, pos: &[i32; 3]) {
+        let encoding = self.players.chunk_encoding(uid);
+        match self.world.get_chunk_data(pos, uid, encoding) {
+            // If the buffer is full or client disconnect, this package will not be send
+            Ok(Some(package)) => _ = self.players.client(uid).try_send(package),
+            Ok(None) => {}
+            Err(e) => eprintln!("Server: Could not read chunk {pos:?}: {e}"),
+        }
+    }
+
+    /// Chunks which could not be written stay loaded and are written with the next unload
+    fn unload_chunks(&mut self) {
+        let positions = self.players.online().map(|p| p.player.pos);
+        if let Err(e) = self.world.unload_chunks(positions) {
+            eprintln!("Server: Could not write unloaded chunks to disk: {e}");
+        }
+    }
+
+    /// Runs the admin command if the sender has the permission, stdin has all of them
+    fn run(&mut self, uid: UID, line: &str) {
+        let permission = if uid == NOUSER {
+            Permission::Operator
+        } else {
+            let player = self.players.get_player_mut(uid);
+            if let Some(command) = line.split_whitespace().next() {
+                eprintln!("Server: {} ran {command}", player.name);
+            }
+            player.permission
+        };
+        let command = match AdminCommand::parse(line) {
+            Ok(command) if command.permission() <= permission => command,
+            Ok(_) => {
+                return self.answer(uid, "You are not allowed to run this command".to_string());
+            }
+            Err(answer) => return self.answer(uid, answer),
+        };
+        if uid != NOUSER && self.target_permission(uid, &command) >= Some(permission) {
+            let answer = "You can not run this command against a player with the same or a higher permission";
+            return self.answer(uid, answer.to_string());
+        }
+
+        let answer = match command {
+            AdminCommand::Help => commands::help(permission),
+            AdminCommand::Kick(name, reason) => {
+                let reason = reason.unwrap_or_else(|| match uid {
+                    NOUSER => "Kicked by the operator".to_string(),
+                    uid => format!("Kicked by {}", self.players.get_player_mut(uid).name),
+                });
+                if self.players.kick(&name, reason) {
+                    format!("Kicked {name}")
+                } else {
+                    format!("{name} is not online")
+                }
+            }
+            AdminCommand::Access(command) => match self.players.access_command(command) {
+                Ok(answer) => answer,
+                Err(e) => format!("{e:#}"),
+            },
+            AdminCommand::Register(name, password) => {
+                // Hashing is slow, the world answers when the hash is sent back
+                let commands = self.commands.clone();
+                std::thread::spawn(move || {
+                    let hash = auth::hash_password(&password);
+                    _ = commands.blocking_send((uid, Command::SetPassword(name, hash)));
+                });
+                return;
+            }
+            AdminCommand::SetPermission(name, level) => {
+                if self.players.set_permission(&name, level) {
+                    format!("Set the permission of {name} to {}", level.name())
+                } else {
+                    format!("{name} is not registered")
+                }
+            }
+            AdminCommand::Pregenerate(from, to) => match self.world.start_pregeneration(from, to) {
+                Ok(()) => "Started the pregeneration".to_string(),
+                Err(e) => format!("Could not start the pregeneration: {e:#}"),
+            },
+            AdminCommand::Shutdown => self.shutdown(),
+        };
+        self.answer(uid, answer);
+    }
+
+    /// Highest permission of the players the command of `uid` acts against,
+    /// None if it acts against nobody else
+    fn target_permission(&self, uid: UID, command: &AdminCommand) -> Option<Permission> {
+        let sender = self.players.online().find(|p| p.uid == uid);
+        match command.target()? {
+            Target::Name(name) if sender.is_some_and(|p| p.player.name == name) => None,
+            Target::Name(name) => Some(self.players.permission(name)),
+            Target::Address(address) => self
+                .players
+                .online()
+                .filter(|p| p.address == address)
+                .map(|p| p.player.permission)
+                .max(),
+        }
+    }
+
+    /// Prints the answer to a command from stdin or sends it to the player
+    fn answer(&self, uid: UID, answer: String) {
+        if uid == NOUSER {
+            for line in answer.lines() {
+                eprintln!("Server: {line}");
+            }
+        } else if self.players.is_online(uid) {
+            let package = ClientBound::CommandAnswer {
+                answer: truncate_answer(answer),
+            };
+            // The answer is lost if the write channel is full
+            _ = self.players.client(uid).try_send(package.to_arc());
+        }
+    }
+
+    fn shutdown(&mut self) -> ! {
+        self.players.sync_to_disk(&self.world_directory).unwrap();
+        self.world.sync_to_disk().unwrap();
+        eprintln!("Server: shut down after saving to disk");
+        std::process::exit(0);
+    }
+
+    /// Checks a block update of the player against the [`rules`],
+    /// whether the block can be placed on the block which is there is checked by the world
+    fn allow_block_update(&mut self, uid: UID, pos: &[i32; 3], block: Block) -> bool {
+        let player = self.players.get_online_mut(uid);
+        if !player.block_updates.try_acquire()
+            || !rules::in_reach(player.player.pos, pos)
+            || !self.world.blocks().is_valid(block)
+        {
+            return false;
+        }
+        // Blocks placed into air must not end up inside a player
+        let placed = !block.is_air() && self.world.get_block(pos).is_some_and(|b| b.is_air());
+        !placed
+            || !self
+                .players
+                .online()
+                .any(|p| rules::intersects_player(p.player.pos, pos))
     }
 }
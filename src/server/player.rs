@@ -1,9 +1,14 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 
-use anyhow::Ok;
 use serde::{Deserialize, Serialize};
 
+use super::access::{Access, AccessCommand};
+use super::auth::{Account, Credential, Registration};
+use super::commands::Permission;
+use super::rules::{BLOCK_UPDATE_BURST, BLOCK_UPDATES_PER_SECOND, RateLimit};
 use super::{Client, UID};
+use crate::net::{ChunkEncoding, MAX_NAME_LENGTH, truncate_reason};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Player {
@@ -11,35 +16,72 @@ pub struct Player {
     pub pos: [f64; 3],
     pub pitch: f32,
     pub yaw: f32,
+    /// Hash of the password, see [`super::auth`]. None for players from before passwords existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Which admin commands the player may run, see [`super::commands`]
+    #[serde(default)]
+    pub permission: Permission,
 }
 
 impl Player {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, password: String) -> Self {
         Player {
             name,
             pos: [0.0, 50.0, 0.0],
             pitch: 0.0,
             yaw: 0.0,
+            password: Some(password),
+            permission: Permission::default(),
         }
     }
 }
 
+/// Names are alphanumeric and not empty
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LENGTH && name.chars().all(char::is_alphanumeric)
+}
+
 #[derive(Debug)]
 pub struct ServerPlayer {
     pub player: Player,
     pub package_writer: Client,
     pub uid: usize,
+    /// Encoding of the chunk packages sent to this player
+    pub chunk_encoding: ChunkEncoding,
+    pub block_updates: RateLimit,
+    /// Stops the reader of the connection, which logs the player out
+    pub disconnect: Option<Disconnect>,
+    /// Address the player connected from
+    pub address: IpAddr,
+}
+
+/// Everything the connection of a player knows when it logs in
+#[derive(Debug)]
+pub struct LoginRequest {
+    pub name: String,
+    /// Checked against [`Players::account`] before
+    pub credential: Credential,
+    pub chunk_encoding: ChunkEncoding,
+    pub client: Client,
+    pub disconnect: Disconnect,
+    pub address: IpAddr,
 }
 
+/// Sends the reason of a kick to the reader of the connection, which sends it to the client
+pub type Disconnect = tokio::sync::oneshot::Sender<String>;
+
 /// Both Vec have to be same length, online is None when not logged in
 #[derive(Debug)]
 pub struct Players {
     registered: Vec<Player>,
     online: Vec<Option<ServerPlayer>>,
+    registration: Registration,
+    access: Access,
 }
 
 impl Players {
-    pub fn new(world_directory: &std::path::Path) -> Self {
+    pub fn new(world_directory: &std::path::Path, registration: Registration) -> Self {
         let player_file =
             std::fs::read_to_string(world_directory.join("players.json")).unwrap_or("[]".into());
 
@@ -47,17 +89,85 @@ impl Players {
             serde_json::from_str(&player_file).expect("Could not parse players.json");
         let online = (0..players.len()).map(|_| None).collect();
 
+        let access =
+            Access::load(world_directory).expect("Could not load the whitelist or the bans");
+
         Players {
             registered: players,
             online,
+            registration,
+            access,
+        }
+    }
+
+    /// What the login of `name` has to check
+    pub fn account(&self, name: &str) -> Account {
+        match self.registered.iter().find(|p| p.name == name) {
+            Some(Player {
+                password: Some(hash),
+                ..
+            }) => Account::Password(hash.clone()),
+            Some(_) => Account::NoPassword,
+            None if self.registration == Registration::Open => Account::Free,
+            None => Account::Closed,
+        }
+    }
+
+    /// Registers the name or replaces its password, also if the player is online
+    pub fn set_password(&mut self, name: String, hash: String) {
+        match self.registered.iter().position(|p| p.name == name) {
+            Some(uid) => {
+                self.registered[uid].password = Some(hash.clone());
+                if let Some(player) = &mut self.online[uid] {
+                    player.player.password = Some(hash);
+                }
+            }
+            None => {
+                self.registered.push(Player::new(name, hash));
+                self.online.push(None);
+            }
         }
     }
 
+    /// Changes the permission, also if the player is online.
+    /// Returns false if the name is not registered.
+    pub fn set_permission(&mut self, name: &str, permission: Permission) -> bool {
+        let Some(uid) = self.registered.iter().position(|p| p.name == name) else {
+            return false;
+        };
+        self.registered[uid].permission = permission;
+        if let Some(player) = &mut self.online[uid] {
+            player.player.permission = permission;
+        }
+        true
+    }
+
+    /// Permission of the name, names which are not registered are players
+    pub fn permission(&self, name: &str) -> Permission {
+        self.registered
+            .iter()
+            .find(|p| p.name == name)
+            .map_or(Permission::Player, |p| p.permission)
+    }
+
     pub fn online(&self) -> impl Iterator<Item = &ServerPlayer> {
         self.online.iter().flatten()
     }
 
-    pub fn login(&mut self, name: String, client: Client) -> Option<UID> {
+    /// The account might have changed since the credential was checked, so it is only accepted
+    /// if it still fits. Fails with the reason for the client.
+    pub fn login(&mut self, request: LoginRequest) -> Result<UID, String> {
+        let LoginRequest {
+            name,
+            credential,
+            chunk_encoding,
+            client,
+            disconnect,
+            address,
+        } = request;
+        self.access.check(&name, address)?;
+        let changed = "The account changed during the login, try again".to_string();
+
         let pos = self
             .registered
             .iter()
@@ -65,27 +175,94 @@ impl Players {
             .find(|(_idx, p)| p.name == name);
         if let Some((pos, player)) = pos {
             //Already registered
+            let mut player = player.clone();
+            match (&player.password, credential) {
+                (Some(hash), Credential::Verified(verified)) if *hash == verified => {}
+                (None, Credential::Register(hash)) => player.password = Some(hash),
+                _ => return Err(changed),
+            }
             if self.online[pos].is_none() {
                 self.online[pos] = Some(ServerPlayer {
-                    player: player.clone(),
+                    player,
                     package_writer: client,
                     uid: pos,
+                    chunk_encoding,
+                    block_updates: RateLimit::new(BLOCK_UPDATES_PER_SECOND, BLOCK_UPDATE_BURST),
+                    disconnect: Some(disconnect),
+                    address,
                 });
-                Some(pos)
+                Ok(pos)
             } else {
-                None
+                Err("The player is already online".to_string())
             }
         } else {
             //Not registered
+            let Credential::Register(hash) = credential else {
+                return Err(changed);
+            };
+            if self.registration != Registration::Open {
+                return Err(changed);
+            }
             let uid = self.registered.len();
-            self.registered.push(Player::new(name));
+            self.registered.push(Player::new(name, hash));
             self.online.push(Some(ServerPlayer {
                 player: self.registered[uid].clone(),
                 package_writer: client,
                 uid,
+                chunk_encoding,
+                block_updates: RateLimit::new(BLOCK_UPDATES_PER_SECOND, BLOCK_UPDATE_BURST),
+                disconnect: Some(disconnect),
+                address,
             }));
-            Some(uid)
+            Ok(uid)
+        }
+    }
+
+    /// Sends the reason to the player and closes its connection.
+    /// The player stays online until its reader sends the logout.
+    /// Returns false if no player with the name is online.
+    pub fn kick(&mut self, name: &str, reason: String) -> bool {
+        let Some(player) = self
+            .online
+            .iter_mut()
+            .flatten()
+            .find(|p| p.player.name == name)
+        else {
+            return false;
+        };
+        if let Some(disconnect) = player.disconnect.take() {
+            // The reader is already gone if the connection was closed
+            _ = disconnect.send(truncate_reason(reason));
+        }
+        true
+    }
+
+    /// Changes the whitelist or the bans and kicks the players who may no longer join.
+    /// Returns the answer for the sender of the command.
+    pub fn access_command(&mut self, command: AccessCommand) -> anyhow::Result<String> {
+        let command = match command {
+            AccessCommand::BanAddressOf(name) => {
+                match self.online().find(|p| p.player.name == name) {
+                    Some(player) => AccessCommand::BanAddress(player.address),
+                    None => AccessCommand::BanAddressOf(name),
+                }
+            }
+            command => command,
+        };
+        let answer = self.access.apply(command)?;
+
+        let kicked = self
+            .online()
+            .filter_map(|p| {
+                let reason = self.access.check(&p.player.name, p.address).err()?;
+                Some((p.player.name.clone(), reason))
+            })
+            .collect::<Vec<_>>();
+        for (name, reason) in kicked {
+            eprintln!("Server: Kicked {name}: {reason}");
+            self.kick(&name, reason);
         }
+        Ok(answer)
     }
 
     pub fn logout(&mut self, uid: UID) {
@@ -102,11 +279,19 @@ impl Players {
 
         let json = serde_json::to_string(&self.registered)?;
 
-        std::fs::write(world_directory.join("players.json"), json)?;
+        crate::misc::write_atomic(&world_directory.join("players.json"), json.as_bytes())?;
 
         Ok(())
     }
 
+    pub fn is_online(&self, uid: UID) -> bool {
+        self.online.get(uid).is_some_and(|p| p.is_some())
+    }
+
+    pub fn chunk_encoding(&self, uid: UID) -> ChunkEncoding {
+        self.online[uid].as_ref().unwrap().chunk_encoding
+    }
+
     pub fn client(&self, uid: UID) -> &Client {
         &self.online[uid].as_ref().unwrap().package_writer
     }
@@ -115,6 +300,10 @@ impl Players {
         &mut self.online[uid].as_mut().unwrap().player
     }
 
+    pub fn get_online_mut(&mut self, uid: UID) -> &mut ServerPlayer {
+        self.online[uid].as_mut().unwrap()
+    }
+
     /// Sends a package to all logged in players
     pub fn broadcast(&self, package: Arc<[u8]>) {
         for player in self.online.iter().flatten() {
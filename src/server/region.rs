@@ -0,0 +1,411 @@
+//! On disk chunk storage split into region files
+//!
+//! A region file holds `REGION_SIZE`^3 chunks. It starts with a header containing one
+//! [`RegionEntry`] per chunk, followed by the chunk data in sectors of `SECTOR_SIZE` bytes.
+//! Chunks can be read and written individually without touching the rest of the world.
+//! Chunk data is never overwritten in place, see [`RegionStorage::flush`].
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::game::ChunkIndex;
+
+/// Number of chunks in each direction of a region
+pub const REGION_SIZE: i32 = 16;
+const CHUNKS_PER_REGION: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const SECTOR_SIZE: u64 = 4096;
+/// Most region files which are open at the same time, each one holds a file descriptor and
+/// its header in memory
+const MAX_OPEN_REGIONS: usize = 64;
+/// Number of sectors occupied by the header
+const HEADER_SECTORS: u32 =
+    ((CHUNKS_PER_REGION * std::mem::size_of::<RegionEntry>()) as u64).div_ceil(SECTOR_SIZE) as u32;
+
+/// Where to find the data of a chunk in the region file
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, IntoBytes, FromBytes, Immutable)]
+pub struct RegionEntry {
+    /// First sector of the chunk data, 0 if the chunk is not stored
+    sector: u32,
+    /// Length of the chunk data in bytes
+    length: u32,
+}
+
+impl RegionEntry {
+    fn sectors(&self) -> std::ops::Range<u32> {
+        let count = (self.length as u64).div_ceil(SECTOR_SIZE) as u32;
+        self.sector..self.sector + count
+    }
+}
+
+/// Returns the region and the index of the chunk inside the region
+fn region_position(pos: &ChunkIndex) -> (ChunkIndex, usize) {
+    let region = pos.map(|x| x.div_euclid(REGION_SIZE));
+    let [x, y, z] = pos.map(|x| x.rem_euclid(REGION_SIZE) as usize);
+    let size = REGION_SIZE as usize;
+    (region, x * size * size + y * size + z)
+}
+
+struct RegionFile {
+    file: File,
+    entries: Vec<RegionEntry>,
+    /// Which sectors of the file are in use, including the header
+    used_sectors: Vec<bool>,
+    /// Entries which changed in memory but not yet in the header on disk
+    uncommitted: Vec<usize>,
+    /// Old locations of rewritten chunks, they are freed after the next commit
+    replaced: Vec<RegionEntry>,
+    /// [`RegionStorage::uses`] when the region was used last
+    last_used: u64,
+}
+
+impl RegionFile {
+    fn open(path: &Path, create: bool) -> std::io::Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path);
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !create => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = vec![RegionEntry::default(); CHUNKS_PER_REGION];
+        let length = file.metadata()?.len();
+        // A new file or one cut off by a crash is shorter than the header,
+        // the missing entries are empty
+        let header = entries.as_mut_bytes();
+        let present = header.len().min(length as usize);
+        file.read_exact(&mut header[..present])?;
+        if length < HEADER_SECTORS as u64 * SECTOR_SIZE {
+            file.set_len(HEADER_SECTORS as u64 * SECTOR_SIZE)?;
+        }
+        // Chunks whose data is not in the file are treated as never stored
+        for entry in entries.iter_mut().filter(|e| e.sector != 0) {
+            let end = entry.sector as u64 * SECTOR_SIZE + entry.length as u64;
+            if entry.sector < HEADER_SECTORS || end > length {
+                *entry = RegionEntry::default();
+            }
+        }
+
+        let mut used_sectors = vec![true; HEADER_SECTORS as usize];
+        for entry in entries.iter().filter(|e| e.sector != 0) {
+            let sectors = entry.sectors();
+            if used_sectors.len() < sectors.end as usize {
+                used_sectors.resize(sectors.end as usize, false);
+            }
+            for sector in sectors {
+                used_sectors[sector as usize] = true;
+            }
+        }
+
+        Ok(Some(RegionFile {
+            file,
+            entries,
+            used_sectors,
+            uncommitted: Vec::new(),
+            replaced: Vec::new(),
+            last_used: 0,
+        }))
+    }
+
+    fn read(&mut self, index: usize) -> std::io::Result<Option<Vec<u8>>> {
+        let entry = self.entries[index];
+        if entry.sector == 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; entry.length as usize];
+        self.file
+            .seek(SeekFrom::Start(entry.sector as u64 * SECTOR_SIZE))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// The data is written to free sectors, the old data stays valid until [`RegionFile::commit`]
+    fn write(&mut self, index: usize, data: &[u8]) -> std::io::Result<()> {
+        let needed = (data.len() as u64).div_ceil(SECTOR_SIZE).max(1) as u32;
+        let sector = self.allocate(needed);
+
+        self.file
+            .seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE))?;
+        self.file.write_all(data)?;
+
+        let old = std::mem::replace(
+            &mut self.entries[index],
+            RegionEntry {
+                sector,
+                length: data.len() as u32,
+            },
+        );
+        if old.sector != 0 {
+            self.replaced.push(old);
+        }
+        self.uncommitted.push(index);
+
+        Ok(())
+    }
+
+    /// Points the header to the newly written data. The data is on disk before the header is
+    /// changed, so a crash at any point leaves either the old or the new chunk in the file.
+    fn commit(&mut self) -> std::io::Result<()> {
+        if self.uncommitted.is_empty() {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+
+        self.uncommitted.sort_unstable();
+        self.uncommitted.dedup();
+        for index in self.uncommitted.drain(..) {
+            self.file.seek(SeekFrom::Start(
+                (index * std::mem::size_of::<RegionEntry>()) as u64,
+            ))?;
+            self.file.write_all(self.entries[index].as_bytes())?;
+        }
+        self.file.sync_data()?;
+
+        // The old data is not referenced by the header anymore
+        for entry in self.replaced.drain(..) {
+            for sector in entry.sectors() {
+                self.used_sectors[sector as usize] = false;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds `count` consecutive free sectors and marks them as used
+    fn allocate(&mut self, count: u32) -> u32 {
+        let count = count as usize;
+        let mut run = 0;
+        for (i, used) in self.used_sectors.iter().enumerate() {
+            if *used {
+                run = 0;
+            } else {
+                run += 1;
+                if run == count {
+                    let start = i + 1 - count;
+                    self.used_sectors[start..=i].fill(true);
+                    return start as u32;
+                }
+            }
+        }
+        // Append at the end of the file, reusing free sectors at the end
+        let start = self.used_sectors.len() - run;
+        self.used_sectors.resize(start + count, true);
+        self.used_sectors[start..].fill(true);
+        start as u32
+    }
+}
+
+/// All region files of a world. Region files are opened on first use, the least recently used
+/// one is closed when more than `MAX_OPEN_REGIONS` would be open.
+pub struct RegionStorage {
+    directory: PathBuf,
+    regions: HashMap<ChunkIndex, RegionFile>,
+    /// Counts the uses of regions, to find the least recently used one
+    uses: u64,
+}
+
+impl RegionStorage {
+    pub fn new(world_directory: &Path) -> std::io::Result<Self> {
+        let directory = world_directory.join("regions");
+        std::fs::create_dir_all(&directory)?;
+        Ok(RegionStorage {
+            directory,
+            regions: HashMap::new(),
+            uses: 0,
+        })
+    }
+
+    fn region_file(
+        &mut self,
+        region: ChunkIndex,
+        create: bool,
+    ) -> std::io::Result<Option<&mut RegionFile>> {
+        if !self.regions.contains_key(&region) {
+            let [x, y, z] = region;
+            let path = self.directory.join(format!("r.{x}.{y}.{z}.bin"));
+            let Some(file) = RegionFile::open(&path, create)? else {
+                return Ok(None);
+            };
+            if self.regions.len() >= MAX_OPEN_REGIONS {
+                self.close_least_recently_used()?;
+            }
+            self.regions.insert(region, file);
+        }
+        self.uses += 1;
+        let file = self.regions.get_mut(&region).expect("Region was opened");
+        file.last_used = self.uses;
+        Ok(Some(file))
+    }
+
+    /// Commits the chunks saved to the region before it is closed
+    fn close_least_recently_used(&mut self) -> std::io::Result<()> {
+        let Some((&region, _)) = self.regions.iter().min_by_key(|(_, file)| file.last_used) else {
+            return Ok(());
+        };
+        self.regions.get_mut(&region).unwrap().commit()?;
+        self.regions.remove(&region);
+        Ok(())
+    }
+
+    /// Reads the stored data of a chunk, None if the chunk was never saved
+    pub fn load(&mut self, pos: &ChunkIndex) -> std::io::Result<Option<Vec<u8>>> {
+        let (region, index) = region_position(pos);
+        match self.region_file(region, false)? {
+            Some(file) => file.read(index),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the chunk was ever saved, without reading its data
+    pub fn contains(&mut self, pos: &ChunkIndex) -> std::io::Result<bool> {
+        let (region, index) = region_position(pos);
+        Ok(self
+            .region_file(region, false)?
+            .is_some_and(|file| file.entries[index].sector != 0))
+    }
+
+    /// Positions of all chunks stored in the region files of the world
+    pub fn stored_chunks(&mut self) -> std::io::Result<Vec<ChunkIndex>> {
+        let mut regions = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let name = entry?.file_name();
+            let Some(coordinates) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("r."))
+                .and_then(|name| name.strip_suffix(".bin"))
+            else {
+                continue;
+            };
+            let coordinates = coordinates
+                .split('.')
+                .map(|c| c.parse::<i32>())
+                .collect::<Result<Vec<_>, _>>();
+            if let Ok(&[x, y, z]) = coordinates.as_deref() {
+                regions.push([x, y, z]);
+            }
+        }
+
+        let size = REGION_SIZE as usize;
+        let mut chunks = Vec::new();
+        for region in regions {
+            let Some(file) = self.region_file(region, false)? else {
+                continue;
+            };
+            for (index, entry) in file.entries.iter().enumerate() {
+                if entry.sector != 0 {
+                    let local = [index / (size * size), index / size % size, index % size];
+                    chunks.push([0, 1, 2].map(|i| region[i] * REGION_SIZE + local[i] as i32));
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Writes the data of a single chunk to its region file.
+    /// It only replaces the old data on disk after the next [`RegionStorage::flush`],
+    /// or when the region file is closed before.
+    pub fn save(&mut self, pos: &ChunkIndex, data: &[u8]) -> std::io::Result<()> {
+        let (region, index) = region_position(pos);
+        self.region_file(region, true)?
+            .expect("Region file is always created")
+            .write(index, data)
+    }
+
+    /// Makes all saved chunks durable on disk
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for region in self.regions.values_mut() {
+            region.commit()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_world(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("infinirust-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn saved_chunks_are_loaded_again() {
+        let directory = temp_world("region-save");
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        regions.save(&[1, -2, 3], b"chunk").unwrap();
+        regions.flush().unwrap();
+
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        assert_eq!(
+            regions.load(&[1, -2, 3]).unwrap().as_deref(),
+            Some(&b"chunk"[..])
+        );
+        assert_eq!(regions.load(&[1, -2, 4]).unwrap(), None);
+        assert_eq!(regions.stored_chunks().unwrap(), vec![[1, -2, 3]]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_regions_are_closed() {
+        let directory = temp_world("region-closed");
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        let count = MAX_OPEN_REGIONS as i32 + 8;
+        for x in 0..count {
+            regions
+                .save(&[x * REGION_SIZE, 0, 0], &x.to_le_bytes())
+                .unwrap();
+            assert!(regions.regions.len() <= MAX_OPEN_REGIONS);
+        }
+        regions.flush().unwrap();
+        drop(regions);
+
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        for x in 0..count {
+            let data = regions.load(&[x * REGION_SIZE, 0, 0]).unwrap();
+            assert_eq!(data.as_deref(), Some(&x.to_le_bytes()[..]));
+        }
+        assert!(regions.regions.len() <= MAX_OPEN_REGIONS);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn truncated_region_file_is_opened() {
+        let directory = temp_world("region-truncated");
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        // The entry of index 0 is at the start of the header, the data at its end
+        regions.save(&[0, 0, 0], b"first").unwrap();
+        regions.save(&[15, 15, 15], b"last").unwrap();
+        regions.flush().unwrap();
+        drop(regions);
+
+        let path = directory.join("regions").join("r.0.0.0.bin");
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        // Cut into the header, behind the first entry
+        file.set_len(100).unwrap();
+        drop(file);
+
+        let mut regions = RegionStorage::new(&directory).unwrap();
+        assert_eq!(regions.load(&[0, 0, 0]).unwrap(), None);
+        assert_eq!(regions.load(&[15, 15, 15]).unwrap(), None);
+        regions.save(&[0, 0, 0], b"again").unwrap();
+        regions.flush().unwrap();
+        assert_eq!(
+            regions.load(&[0, 0, 0]).unwrap().as_deref(),
+            Some(&b"again"[..])
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Rules for the blocks players change
+//!
+//! Clients apply their block updates right away. The server checks every update against
+//! these rules and answers a rejected one with [`ClientBound::BlockUpdateRejected`](crate::net::ClientBound),
+//! which reverts the block on the client.
+
+use std::time::Instant;
+
+/// Furthest distance from the camera of the player to a block it changes.
+/// The client reaches 7 blocks, the rest allows for positions which arrive late.
+pub const MAX_REACH: f64 = 8.0;
+
+/// Block updates a player may send per second on average
+pub const BLOCK_UPDATES_PER_SECOND: f64 = 20.0;
+
+/// Block updates a player may send at once after a pause
+pub const BLOCK_UPDATE_BURST: f64 = 20.0;
+
+/// Bounding box of a player relative to the camera position it sends, matches the client
+const PLAYER_BOX_MIN: [f64; 3] = [-0.3, -1.5, -0.3];
+const PLAYER_BOX_MAX: [f64; 3] = [0.3, 0.125, 0.3];
+
+/// Whether the block is within reach of a player with the camera at `camera`.
+/// The camera is the position the client reports, its movement is not validated, so a modified
+/// client can still teleport next to a block before it changes it.
+pub fn in_reach(camera: [f64; 3], block: &[i32; 3]) -> bool {
+    // Distance to the closest point of the block
+    let distance_squared: f64 = (0..3)
+        .map(|i| {
+            let closest = camera[i].clamp(block[i] as f64, block[i] as f64 + 1.0);
+            (camera[i] - closest).powi(2)
+        })
+        .sum();
+    distance_squared <= MAX_REACH * MAX_REACH
+}
+
+/// Whether the block overlaps a player with the camera at `camera`
+pub fn intersects_player(camera: [f64; 3], block: &[i32; 3]) -> bool {
+    (0..3).all(|i| {
+        let min = camera[i] + PLAYER_BOX_MIN[i];
+        let max = camera[i] + PLAYER_BOX_MAX[i];
+        (block[i] as f64) < max && min < block[i] as f64 + 1.0
+    })
+}
+
+/// Token bucket which allows `rate` actions per second and `burst` actions at once
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimit {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// True if the whole burst is available again
+    pub fn is_full(&self) -> bool {
+        self.tokens + self.last.elapsed().as_secs_f64() * self.rate >= self.burst
+    }
+
+    /// Uses up one action, false if the limit is reached
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -4,7 +4,8 @@ use crate::server::NOUSER;
 
 use super::ServerCommand;
 
-/// Supposed to be started in its own thread handling sdtin in a blocking way
+/// Supposed to be started in its own thread handling sdtin in a blocking way.
+/// Every line except `bind` is an admin command, see [`super::commands`].
 pub fn handle_stdin(server: ServerCommand, bind: String) {
     let stdin = std::io::stdin();
     for line in stdin.lock().lines() {
@@ -13,21 +14,15 @@ pub fn handle_stdin(server: ServerCommand, bind: String) {
             eprintln!("Server: IO error in stdin: {e}");
             panic!();
         });
-        match command.as_str() {
-            "exit" => {
-                //If the server is already down exit the process
-                server
-                    .blocking_send((NOUSER, super::Command::Shutdown))
-                    .unwrap_or_else(|_| std::process::exit(1));
-            }
-            "bind" => {
-                //Writes the address to connect to on stdout
-                println!("{bind}");
-                std::io::stdout().flush().unwrap();
-            }
-            _ => {
-                eprintln!("Server: Unknown command");
-            }
+        if command.trim() == "bind" {
+            //Writes the address to connect to on stdout
+            println!("{bind}");
+            std::io::stdout().flush().unwrap();
+        } else {
+            //If the server is already down exit the process
+            server
+                .blocking_send((NOUSER, super::Command::Run(command)))
+                .unwrap_or_else(|_| std::process::exit(1));
         }
     }
     //Reached EOF, if the server is already down exit the process
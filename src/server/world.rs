@@ -1,198 +1,815 @@
 const CHUNK_SIZE: usize = crate::game::CHUNK_SIZE as usize;
-use crate::game::{LocalBlockIndex, Y_RANGE};
+use crate::game::blocks::BlocksConfig;
+use crate::game::chunk::local_block_index;
+use crate::game::palette::{CHUNK_VOLUME, PalettedBlocks};
+use crate::game::{Block, BlockType, LocalBlockIndex};
 
 use std::fs::File;
-use std::io::{Read, Write};
-use std::{collections::HashMap, sync::Arc};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-use noise::Perlin;
-use noise::{BasicMulti, NoiseFn, Seedable};
+use anyhow::{Context, bail};
 use zerocopy::IntoBytes;
 
+use super::auth::Registration;
+use super::biome::Biome;
+use super::generation::GenerationPool;
+use super::region::RegionStorage;
+use super::world_generator::{
+    FlatGenerator, GeneratorSettings, LegacyGenerator, Ore, OreSettings, TerrainGenerator,
+    TerrainMode, VoidGenerator, WorldGenerator, default_ores,
+};
+use super::{ServerCommand, UID};
+use crate::net::{ChunkEncoding, ClientBound, Package, run_length_size};
+
+/// Version of the world directory layout written by this binary.
+/// Every change to the chunk layout or the block ids has to increase it and add a migration.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// `MIGRATIONS[n]` upgrades a world directory from format version `n` to `n + 1`
+const MIGRATIONS: [fn(&Path) -> anyhow::Result<()>; FORMAT_VERSION as usize] = [
+    migrate_chunks_dat_to_regions,
+    migrate_chunks_to_palette,
+    migrate_block_states,
+];
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Settings {
     seed: u32,
+    /// Worlds created before the format was versioned have version 0
+    #[serde(default)]
+    format_version: u32,
+    /// How many chunks the server keeps in memory before it unloads chunks far away from players
+    #[serde(default = "default_max_loaded_chunks")]
+    max_loaded_chunks: usize,
+    /// Chunks within this distance (in chunks) of an online player are never unloaded
+    #[serde(default = "default_unload_distance")]
+    unload_distance: i32,
+    /// Seconds between automatic saves of the world and the players, 0 disables autosave
+    #[serde(default = "default_autosave_interval")]
+    autosave_interval: u64,
+    /// Number of threads which generate new chunks, 0 uses one thread per CPU core
+    #[serde(default)]
+    generation_threads: usize,
+    /// Which kind of world is generated
+    #[serde(default)]
+    generator: GeneratorSettings,
+    /// Shape of the generated terrain, only used by the terrain generator
+    #[serde(default)]
+    terrain: TerrainMode,
+    /// Ore veins generated in the stone, only used by the terrain generator
+    #[serde(default = "default_ores")]
+    ores: Vec<OreSettings>,
+    /// Who may register new player names
+    #[serde(default)]
+    registration: Registration,
+}
+
+/// 16384 chunks are 64 MiB of block data
+fn default_max_loaded_chunks() -> usize {
+    16384
+}
+
+/// A bit more than the view distance of the client
+fn default_unload_distance() -> i32 {
+    10
+}
+
+fn default_autosave_interval() -> u64 {
+    300
+}
+
+/// Number of chunks the pregeneration keeps queued at the workers.
+/// Chunks requested by players are queued behind them, so this should stay small.
+const PREGENERATION_QUEUE: usize = 64;
+/// Time between two progress messages of the pregeneration
+const PREGENERATION_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of the `pregen` command
+struct Pregeneration {
+    /// Chunks which are not checked yet
+    remaining: ChunkArea,
+    /// Chunks which are being generated for the pregeneration
+    in_flight: HashSet<[i32; 3]>,
+    total: u64,
+    done: u64,
+    last_report: Instant,
+}
+
+/// The chunk positions of a box, walked without storing them.
+/// y changes fastest, then z, then x.
+struct ChunkArea {
+    from: [i32; 3],
+    to: [i32; 3],
+    next: Option<[i32; 3]>,
+}
+
+impl ChunkArea {
+    /// Both corners are included
+    fn new(from: [i32; 3], to: [i32; 3]) -> Self {
+        let empty = (0..3).any(|i| from[i] > to[i]);
+        ChunkArea {
+            from,
+            to,
+            next: (!empty).then_some(from),
+        }
+    }
+
+    /// Number of chunks in the whole box, saturated for absurdly large boxes
+    fn size(&self) -> u64 {
+        (0..3)
+            .map(|i| (self.to[i] as i64 - self.from[i] as i64 + 1).max(0) as u64)
+            .fold(1, u64::saturating_mul)
+    }
 }
 
+impl Iterator for ChunkArea {
+    type Item = [i32; 3];
+
+    fn next(&mut self) -> Option<[i32; 3]> {
+        let pos = self.next?;
+        let mut next = pos;
+        self.next = None;
+        for i in [1, 2, 0] {
+            if next[i] < self.to[i] {
+                next[i] += 1;
+                self.next = Some(next);
+                break;
+            }
+            next[i] = self.from[i];
+        }
+        Some(pos)
+    }
+}
+
+#[derive(Debug)]
 pub struct ChunkData {
-    blocks: Vec<u8>,
+    blocks: PalettedBlocks,
+    /// The chunk differs from what the generator creates, so it has to be stored on disk
+    modified: bool,
+    /// The chunk changed since it was last written to disk
+    dirty: bool,
+    /// A copy is written by the running autosave, the chunk is clean afterwards unless it changed
+    autosaving: bool,
 }
 
 impl ChunkData {
     pub fn empty() -> Self {
         ChunkData {
-            blocks: vec![0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
-        }
-    }
-
-    pub fn generate(generator: &BasicMulti<Perlin>, pos: &[i32; 3]) -> Self {
-        let mut chunk = Self::empty();
-
-        let [x, y, z] = pos;
-
-        for xx in 0..CHUNK_SIZE {
-            for zz in 0..CHUNK_SIZE {
-                let x = (x * CHUNK_SIZE as i32 + xx as i32) as f64 + 0.5;
-                let z = (z * CHUNK_SIZE as i32 + zz as i32) as f64 + 0.5;
-                let height =
-                    generator.get([x / 100.0, z / 100.0]) * Y_RANGE as f64 * CHUNK_SIZE as f64 * 0.50;
-                for yy in 0..CHUNK_SIZE {
-                    let y = (y * CHUNK_SIZE as i32 + yy as i32) as f64 + 0.5;
-                    if y <= height {
-                        chunk.set([xx, yy, zz], 1);
-                    }
-                    if y <= height - 1.0 {
-                        // Add some dirt below the grass
-                        chunk.set([xx, yy, zz], 2);
-                    }
-                    if y <= height - 5.0 {
-                        // Add some stone below the dirt
-                        chunk.set([xx, yy, zz], 3);
-                    }
-                }
-            }
+            blocks: PalettedBlocks::filled(Block::AIR),
+            modified: false,
+            dirty: false,
+            autosaving: false,
         }
+    }
 
-        chunk
+    /// Creates the chunk from the data stored in a region file
+    pub fn from_disk(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(ChunkData {
+            blocks: PalettedBlocks::from_bytes(data)?,
+            modified: true,
+            dirty: false,
+            autosaving: false,
+        })
     }
 
-    pub fn get(&self, pos: LocalBlockIndex) -> u8 {
-        let chunk_size_usize: usize = CHUNK_SIZE;
-        self.blocks[pos[0] as usize * chunk_size_usize * chunk_size_usize
-            + pos[1] as usize * chunk_size_usize
-            + pos[2] as usize]
+    /// The data which is stored in a region file
+    pub fn to_disk(&self) -> Vec<u8> {
+        self.blocks.to_bytes()
     }
 
-    pub fn set(&mut self, pos: [usize; 3], block: u8) {
-        self.blocks[pos[0] * CHUNK_SIZE * CHUNK_SIZE + pos[1] * CHUNK_SIZE + pos[2]] = block
+    /// Id of the block
+    pub fn get(&self, pos: LocalBlockIndex) -> BlockType {
+        self.block(pos).id
     }
 
-    pub fn block_mut(&mut self, pos: LocalBlockIndex) -> &mut u8 {
-        let chunk_size_usize: usize = CHUNK_SIZE;
-        &mut self.blocks[pos[0] as usize * chunk_size_usize * chunk_size_usize
-            + pos[1] as usize * chunk_size_usize
-            + pos[2] as usize]
+    /// Id and state of the block
+    pub fn block(&self, pos: LocalBlockIndex) -> Block {
+        self.blocks.get(local_block_index(pos))
     }
-}
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-pub struct ChunkMeta {
-    pos: [i32; 3],
-    /// Where to find the chunk data in the chunk file
-    offset: usize,
+    /// Sets the block in its default state
+    pub fn set(&mut self, pos: [usize; 3], block: BlockType) {
+        self.blocks.set(
+            pos[0] * CHUNK_SIZE * CHUNK_SIZE + pos[1] * CHUNK_SIZE + pos[2],
+            Block::new(block),
+        )
+    }
+
+    /// Changes a block of the chunk after generation and marks the chunk as dirty
+    pub fn update(&mut self, pos: LocalBlockIndex, block: Block) {
+        self.blocks.set(local_block_index(pos), block);
+        self.modified = true;
+        self.dirty = true;
+        self.autosaving = false;
+    }
 }
 
 pub struct ServerWorld {
-    generator: BasicMulti<Perlin>,
+    settings: Settings,
+    generator: Arc<dyn WorldGenerator>,
+    generation: GenerationPool,
+    blocks: BlocksConfig,
     loaded_chunks: HashMap<[i32; 3], ChunkData>,
+    /// Chunks which are being generated and the players waiting for them
+    generating: HashMap<[i32; 3], Vec<UID>>,
+    pregeneration: Option<Pregeneration>,
+    /// Shared with the autosave thread, which holds the lock while it writes
+    regions: Arc<Mutex<RegionStorage>>,
+    autosave_running: bool,
+    /// Number of loaded chunks at which the next unload pass is done
+    next_unload: usize,
 }
 
 impl ServerWorld {
-    pub fn from_files(world_directory: &std::path::Path) -> Self {
+    /// Loads the world and upgrades it to the current format if it was written by an older version.
+    /// Generated chunks are sent to `commands` as [`super::Command::ChunkGenerated`].
+    pub fn from_files(world_directory: &Path, commands: ServerCommand) -> anyhow::Result<Self> {
         let settings_file = std::fs::read_to_string(world_directory.join("settings.json"))
-            .expect("Could not open settings.json");
-        let settings: Settings =
-            serde_json::from_str(&settings_file).expect("Could not parse settings.json");
-
-        // Load chunk data from file
-        let loaded_chunks =
-            if let Ok(mut chunk_data) = File::open(world_directory.join("chunks.dat")) {
-                let mut loaded_chunks = HashMap::new();
-                let mut pos = [0i32; 3];
-                while chunk_data.read_exact(pos.as_mut_bytes()).is_ok() {
-                    let mut chunk = ChunkData::empty();
-                    chunk_data
-                        .read_exact(&mut chunk.blocks)
-                        .expect("Could not read chunk data");
-                    loaded_chunks.insert(pos, chunk);
-                }
-                loaded_chunks
-            } else {
-                HashMap::new()
-            };
+            .context("Could not open settings.json")?;
+        let mut settings: Settings =
+            serde_json::from_str(&settings_file).context("Could not parse settings.json")?;
+
+        migrate_world(world_directory, &mut settings)?;
+
+        let regions = RegionStorage::new(world_directory).context("Could not open regions")?;
 
-        let mut generator = BasicMulti::<Perlin>::default();
-        generator.octaves = 5;
-        generator.persistence = 0.25;
-        generator = generator.set_seed(settings.seed);
+        let (blocks, _) = BlocksConfig::new(Path::new("config/blocks.json"));
+        let generator: Arc<dyn WorldGenerator> = create_generator(&settings, &blocks)?.into();
+        let generation =
+            GenerationPool::new(generator.clone(), settings.generation_threads, commands);
 
-        ServerWorld {
+        Ok(ServerWorld {
+            next_unload: settings.max_loaded_chunks,
+            settings,
             generator,
-            loaded_chunks,
-        }
+            generation,
+            blocks,
+            loaded_chunks: HashMap::new(),
+            generating: HashMap::new(),
+            pregeneration: None,
+            regions: Arc::new(Mutex::new(regions)),
+            autosave_running: false,
+        })
     }
 
-    pub fn sync_to_disk(&self, world_directory: &std::path::Path) -> std::io::Result<()> {
-        // Save chunk data
-        let mut chunk_file = File::create(world_directory.join("chunks.dat"))?;
-        for (pos, chunk) in &self.loaded_chunks {
-            chunk_file.write_all(pos.as_bytes())?;
-            chunk_file.write_all(&chunk.blocks)?;
+    /// Unloads the chunks which are far away from all players, if more than
+    /// `max_loaded_chunks` are in memory. Dirty chunks are written to their region file,
+    /// chunks which were never modified are dropped and generated again when needed.
+    pub fn unload_chunks(
+        &mut self,
+        player_positions: impl Iterator<Item = [f64; 3]>,
+    ) -> std::io::Result<()> {
+        if self.loaded_chunks.len() <= self.next_unload {
+            return Ok(());
         }
 
+        let player_chunks = player_positions
+            .map(|pos| pos.map(|x| (x.floor() as i32).div_euclid(CHUNK_SIZE as i32)))
+            .collect::<Vec<_>>();
+        let distance = self.settings.unload_distance;
+
+        let far_chunks = self
+            .loaded_chunks
+            .keys()
+            .filter(|pos| {
+                player_chunks.iter().all(|player| {
+                    pos.iter()
+                        .zip(player)
+                        .any(|(chunk, player)| (chunk - player).abs() > distance)
+                })
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Chunks are only removed once they are written, a failed write keeps them loaded
+        let mut regions = self.regions.lock().unwrap();
+        let mut count = 0;
+        for pos in far_chunks {
+            let chunk = &self.loaded_chunks[&pos];
+            if chunk.dirty {
+                regions.save(&pos, &chunk.to_disk())?;
+            }
+            self.loaded_chunks.remove(&pos);
+            count += 1;
+        }
+        regions.flush()?;
+        drop(regions);
+        eprintln!(
+            "Server: unloaded {count} chunks, {} chunks remain loaded",
+            self.loaded_chunks.len()
+        );
+
+        // Do not check again on every chunk request if the players need more chunks than the budget
+        let max_loaded_chunks = self.settings.max_loaded_chunks;
+        self.next_unload = self.loaded_chunks.len().max(max_loaded_chunks) + max_loaded_chunks / 16;
+
         Ok(())
     }
 
-    /// Gets a reference to a block or None if this position is not loaded
-    pub fn get_block_mut(&mut self, pos: &[i32; 3]) -> Option<&mut u8> {
-        let (chunk_pos, in_chunk_pos) = crate::game::chunk::block_position_to_chunk_index(*pos);
-        if let Some(chunk) = self.loaded_chunks.get_mut(&chunk_pos) {
-            Some(chunk.block_mut(in_chunk_pos))
-        } else {
-            None
+    /// Biome of the block column x, z
+    pub fn biome(&self, x: i32, z: i32) -> Biome {
+        self.generator.biome(x, z)
+    }
+
+    pub fn registration(&self) -> Registration {
+        self.settings.registration
+    }
+
+    pub fn autosave_interval(&self) -> Option<std::time::Duration> {
+        (self.settings.autosave_interval > 0)
+            .then(|| std::time::Duration::from_secs(self.settings.autosave_interval))
+    }
+
+    /// Writes every dirty chunk to its region file, waits for a running autosave first.
+    /// A crash during the save leaves every chunk either in the old or the new state.
+    pub fn sync_to_disk(&mut self) -> std::io::Result<()> {
+        let mut regions = self.regions.lock().unwrap();
+        for (pos, chunk) in self.loaded_chunks.iter_mut().filter(|(_, c)| c.dirty) {
+            regions.save(pos, &chunk.to_disk())?;
+            chunk.dirty = false;
         }
+        regions.flush()
     }
 
-    pub fn get_chunk_data(&mut self, pos: &[i32; 3]) -> Arc<[u8]> {
+    /// Copies the dirty chunks and writes them to their region files in a new thread.
+    /// It sends [`super::Command::Autosaved`] to `commands` when it is done.
+    /// Chunks are read and written after the autosave, so it never overwrites newer data.
+    pub fn start_autosave(&mut self, commands: ServerCommand) {
+        if self.autosave_running {
+            eprintln!("Server: skipped the autosave, the last one is still running");
+            return;
+        }
+
+        let chunks = self
+            .loaded_chunks
+            .iter_mut()
+            .filter(|(_, chunk)| chunk.dirty)
+            .map(|(pos, chunk)| {
+                chunk.autosaving = true;
+                (*pos, chunk.blocks.clone())
+            })
+            .collect::<Vec<_>>();
+
+        let regions = self.regions.clone();
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut regions = regions.lock().unwrap();
+            _ = locked_tx.send(());
+            let count = chunks.len();
+            let result = chunks
+                .into_iter()
+                .try_for_each(|(pos, blocks)| regions.save(&pos, &blocks.to_bytes()))
+                .and_then(|()| regions.flush());
+            drop(regions);
+            let success = match result {
+                Ok(()) => {
+                    eprintln!("Server: autosave of {count} chunks finished");
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Server: autosave of world failed: {e}");
+                    false
+                }
+            };
+            _ = commands.blocking_send((super::NOUSER, super::Command::Autosaved(success)));
+        });
+        // The world waits until the autosave has the lock, so its next write comes after it
+        _ = locked_rx.recv();
+        self.autosave_running = true;
+    }
+
+    /// Marks the chunks written by the autosave as clean, unless they changed in the meantime
+    pub fn finish_autosave(&mut self, success: bool) {
+        self.autosave_running = false;
+        for chunk in self.loaded_chunks.values_mut() {
+            if success && chunk.autosaving {
+                chunk.dirty = false;
+            }
+            chunk.autosaving = false;
+        }
+    }
+
+    /// The chunk package, chunks which are not in memory are loaded from the region files.
+    /// Returns None if the chunk has to be generated first because it was never stored or is
+    /// corrupt, `uid` is then returned by [`ServerWorld::finish_generation`] when it is done.
+    /// Fails if the region file can not be read.
+    pub fn get_chunk_data(
+        &mut self,
+        pos: &[i32; 3],
+        uid: UID,
+        encoding: ChunkEncoding,
+    ) -> std::io::Result<Option<Arc<[u8]>>> {
         if let Some(chunk) = self.loaded_chunks.get(pos) {
-            create_chunk_package(chunk, pos)
-        } else {
-            let new_chunk = ChunkData::generate(&self.generator, pos);
-            let package = create_chunk_package(&new_chunk, pos);
-            self.loaded_chunks.insert(*pos, new_chunk);
-            package
+            return Ok(Some(create_chunk_package(chunk, pos, encoding)));
+        }
+        if let Some(waiting) = self.generating.get_mut(pos) {
+            waiting.push(uid);
+            return Ok(None);
+        }
+        let stored = self.regions.lock().unwrap().load(pos)?.and_then(|data| {
+            ChunkData::from_disk(&data)
+                .inspect_err(|e| {
+                    eprintln!("Server: Chunk {pos:?} is corrupt, it is generated again: {e:#}")
+                })
+                .ok()
+        });
+        match stored {
+            Some(chunk) => {
+                let package = create_chunk_package(&chunk, pos, encoding);
+                self.loaded_chunks.insert(*pos, chunk);
+                Ok(Some(package))
+            }
+            None => {
+                self.generating.insert(*pos, vec![uid]);
+                self.generation.generate(*pos);
+                Ok(None)
+            }
         }
     }
-    /// If the block is in unloaded chunks it will be ignored
-    pub fn process_block_update(&mut self, pos: &[i32; 3], new_block: u8) -> Arc<[u8]> {
-        //Send empty package if block is in unloaded chunk
-        if let Some(block) = self.get_block_mut(pos) {
-            if new_block == 0 {
-                //Destroy
-                // This will always succed and leave an empty block
-                *block = 0;
-                create_block_update_package(pos, 0)
-            } else {
-                //Place
-                // This will only succeed when the block is empty before
-                if *block == 0 {
-                    *block = new_block;
-                    create_block_update_package(pos, new_block)
-                } else {
-                    create_block_update_package(pos, *block)
+
+    /// Loads a chunk generated by the workers, chunks of the pregeneration are written to disk.
+    /// Returns the players waiting for the chunk, the chunk stays loaded if there are any.
+    /// If the pregeneration can not write to disk it is stopped, the chunk is still loaded.
+    pub fn finish_generation(&mut self, pos: &[i32; 3], mut chunk: ChunkData) -> Vec<UID> {
+        let waiting = self.generating.remove(pos).unwrap_or_default();
+
+        let pregenerated = self
+            .pregeneration
+            .as_mut()
+            .is_some_and(|p| p.in_flight.remove(pos));
+        let mut stored = false;
+        if pregenerated {
+            let saved = self.regions.lock().unwrap().save(pos, &chunk.to_disk());
+            let result = saved.and_then(|()| {
+                // The chunk is stored now, like a chunk loaded from disk
+                chunk.modified = true;
+                stored = true;
+                if let Some(pregeneration) = &mut self.pregeneration {
+                    pregeneration.done += 1;
+                }
+                self.continue_pregeneration()
+            });
+            if let Err(e) = result {
+                eprintln!("Server: pregeneration stopped, could not write to disk: {e}");
+                self.pregeneration = None;
+            }
+        }
+
+        // Pregenerated chunks nobody asked for stay on disk only
+        if !stored || !waiting.is_empty() {
+            self.loaded_chunks.insert(*pos, chunk);
+        }
+        waiting
+    }
+
+    /// Generates and saves all chunks between `from` and `to` (inclusive) which are not stored yet.
+    /// The chunks are generated in the background, progress is reported on stderr.
+    /// Fails if a pregeneration is already running.
+    pub fn start_pregeneration(&mut self, from: [i32; 3], to: [i32; 3]) -> anyhow::Result<()> {
+        if self.pregeneration.is_some() {
+            bail!("a pregeneration is already running");
+        }
+
+        let chunks = ChunkArea::new(from, to);
+        eprintln!("Server: pregenerating {} chunks", chunks.size());
+
+        self.pregeneration = Some(Pregeneration {
+            total: chunks.size(),
+            remaining: chunks,
+            in_flight: HashSet::new(),
+            done: 0,
+            last_report: Instant::now(),
+        });
+        Ok(self.continue_pregeneration()?)
+    }
+
+    /// Queues chunks of the pregeneration until `PREGENERATION_QUEUE` chunks are in flight
+    fn continue_pregeneration(&mut self) -> std::io::Result<()> {
+        let Some(pregeneration) = &mut self.pregeneration else {
+            return Ok(());
+        };
+
+        let mut regions = self.regions.lock().unwrap();
+        while pregeneration.in_flight.len() < PREGENERATION_QUEUE {
+            let Some(pos) = pregeneration.remaining.next() else {
+                break;
+            };
+            if self.generating.contains_key(&pos) {
+                // Already generated for a player, it is saved when it arrives
+                pregeneration.in_flight.insert(pos);
+            } else if let Some(chunk) = self.loaded_chunks.get_mut(&pos) {
+                if !chunk.modified || chunk.dirty {
+                    regions.save(&pos, &chunk.to_disk())?;
+                    chunk.modified = true;
+                    chunk.dirty = false;
                 }
+                pregeneration.done += 1;
+            } else if regions.contains(&pos)? {
+                pregeneration.done += 1;
+            } else {
+                self.generating.insert(pos, Vec::new());
+                self.generation.generate(pos);
+                pregeneration.in_flight.insert(pos);
+            }
+        }
+
+        let finished = pregeneration.in_flight.is_empty();
+        if finished || pregeneration.last_report.elapsed() >= PREGENERATION_REPORT_INTERVAL {
+            // Make the progress durable, so an interrupted pregeneration does not start over
+            regions.flush()?;
+            eprintln!(
+                "Server: pregenerated {}/{} chunks ({:.1}%)",
+                pregeneration.done,
+                pregeneration.total,
+                100.0 * pregeneration.done as f64 / pregeneration.total.max(1) as f64
+            );
+            pregeneration.last_report = Instant::now();
+        }
+        if finished {
+            eprintln!("Server: pregeneration finished");
+            self.pregeneration = None;
+        }
+        Ok(())
+    }
+
+    /// The block if its chunk is loaded
+    pub fn get_block(&self, pos: &[i32; 3]) -> Option<Block> {
+        let (chunk_pos, in_chunk_pos) = crate::game::chunk::block_position_to_chunk_index(*pos);
+        Some(self.loaded_chunks.get(&chunk_pos)?.block(in_chunk_pos))
+    }
+
+    /// Blocks known to the server
+    pub fn blocks(&self) -> &BlocksConfig {
+        &self.blocks
+    }
+
+    /// Returns whether the update was applied.
+    /// If the block is in unloaded chunks it will be ignored
+    pub fn process_block_update(&mut self, pos: &[i32; 3], new_block: Block) -> bool {
+        let (chunk_pos, in_chunk_pos) = crate::game::chunk::block_position_to_chunk_index(*pos);
+        let Some(chunk) = self.loaded_chunks.get_mut(&chunk_pos) else {
+            return false;
+        };
+        let block = chunk.block(in_chunk_pos);
+        if new_block.is_air() {
+            //Destroy
+            // This will always succed and leave an empty block
+            if !block.is_air() {
+                chunk.update(in_chunk_pos, Block::AIR);
             }
+            true
         } else {
-            Arc::new([])
+            //Place or change the state
+            // This will only succeed when the block is empty before or has the same id
+            if block.is_air() || block.id == new_block.id {
+                if block != new_block {
+                    chunk.update(in_chunk_pos, new_block);
+                }
+                true
+            } else {
+                false
+            }
         }
     }
 }
 
-/// The package has 2 bytes with the package id 0x0A 0x00, 12 bytes of position and 4096 bytes of chunk data
-fn create_chunk_package(chunk: &ChunkData, pos: &[i32; 3]) -> Arc<[u8]> {
-    let mut package = [0u8; 2 + 12 + 4096];
-    package[0] = 0x0A;
-    package[2..14].copy_from_slice(pos.as_bytes());
-    package[14..].copy_from_slice(&chunk.blocks);
-    Arc::from(package)
-    //Todo: Check if this is efficient
+/// Creates the generator selected in the settings
+fn create_generator(
+    settings: &Settings,
+    blocks: &BlocksConfig,
+) -> anyhow::Result<Box<dyn WorldGenerator>> {
+    let block_id = |name: &str| {
+        blocks
+            .id(name)
+            .with_context(|| format!("Unknown block {name} in settings.json"))
+    };
+
+    Ok(match &settings.generator {
+        GeneratorSettings::Terrain => {
+            let ores = settings
+                .ores
+                .iter()
+                .map(|ore| {
+                    Ok(Ore {
+                        block: block_id(&ore.block)?,
+                        settings: ore.clone(),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Box::new(TerrainGenerator::new(settings.seed, settings.terrain, ores))
+        }
+        GeneratorSettings::Flat { layers } => {
+            let layers = layers
+                .iter()
+                .map(|layer| Ok((block_id(&layer.block)?, layer.height)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Box::new(FlatGenerator::new(&layers))
+        }
+        GeneratorSettings::Void => Box::new(VoidGenerator),
+        GeneratorSettings::Legacy => Box::new(LegacyGenerator::new(settings.seed)),
+    })
+}
+
+/// Brings the world directory to `FORMAT_VERSION`, saving the settings after every step
+fn migrate_world(world_directory: &Path, settings: &mut Settings) -> anyhow::Result<()> {
+    if settings.format_version > FORMAT_VERSION {
+        bail!(
+            "The world has format version {}, but this server only supports up to version {FORMAT_VERSION}. Use a newer server.",
+            settings.format_version
+        );
+    }
+
+    // Worlds from before the format was versioned have no generator in settings.json,
+    // they were generated by the first generator and have to keep it. The choice is saved
+    // before the migration moves chunks.dat, so an interrupted migration does not lose it.
+    if settings.format_version == 0 && has_stored_chunks(world_directory)? {
+        eprintln!("Server: the world keeps the terrain generator of the first release");
+        settings.generator = GeneratorSettings::Legacy;
+        save_settings(world_directory, settings)?;
+    }
+
+    while settings.format_version < FORMAT_VERSION {
+        let version = settings.format_version;
+        eprintln!(
+            "Server: migrating world from format version {version} to {}",
+            version + 1
+        );
+        MIGRATIONS[version as usize](world_directory)
+            .with_context(|| format!("Migration from format version {version} failed"))?;
+
+        settings.format_version += 1;
+        save_settings(world_directory, settings)?;
+    }
+
+    Ok(())
 }
 
-fn create_block_update_package(pos: &[i32; 3], block: u8) -> Arc<[u8]> {
-    let mut package = [0u8; 2 + 12 + 4];
-    package[0] = 0x0B;
-    package[2..14].copy_from_slice(pos.as_bytes());
-    package[14] = block;
-    Arc::from(package)
-    //Todo: Check if this is efficient
+/// Whether a world of format version 0 has chunks, also if a migration of it was interrupted
+fn has_stored_chunks(world_directory: &Path) -> anyhow::Result<bool> {
+    if ["chunks.dat", "chunks.dat.bak"]
+        .iter()
+        .any(|file| world_directory.join(file).exists())
+    {
+        return Ok(true);
+    }
+    match std::fs::read_dir(world_directory.join("regions")) {
+        Ok(mut regions) => Ok(regions.next().is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context("Could not read the regions directory"),
+    }
+}
+
+fn save_settings(world_directory: &Path, settings: &Settings) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(settings)?;
+    crate::misc::write_atomic(&world_directory.join("settings.json"), json.as_bytes())
+        .context("Could not write settings.json")
+}
+
+/// Version 0 -> 1: Moves the chunks of the old `chunks.dat` format into the region files.
+/// The old file is kept as `chunks.dat.bak`.
+fn migrate_chunks_dat_to_regions(world_directory: &Path) -> anyhow::Result<()> {
+    let legacy_file = world_directory.join("chunks.dat");
+    let Ok(mut chunk_data) = File::open(&legacy_file) else {
+        return Ok(());
+    };
+    let mut regions = RegionStorage::new(world_directory)?;
+
+    let mut pos = [0i32; 3];
+    let mut blocks = [0u8; CHUNK_VOLUME];
+    let mut count = 0;
+    while chunk_data.read_exact(pos.as_mut_bytes()).is_ok() {
+        // Version 1 stores the 4096 bytes of the chunk as they are
+        chunk_data.read_exact(&mut blocks)?;
+        regions.save(&pos, &blocks)?;
+        count += 1;
+    }
+    regions.flush()?;
+
+    std::fs::rename(&legacy_file, world_directory.join("chunks.dat.bak"))?;
+    eprintln!("Server: imported {count} chunks from chunks.dat into region files");
+
+    Ok(())
+}
+
+/// Version 1 -> 2: Converts the chunks from one byte per block to the palette format,
+/// which allows block ids larger than 255
+fn migrate_chunks_to_palette(world_directory: &Path) -> anyhow::Result<()> {
+    let mut regions = RegionStorage::new(world_directory)?;
+
+    let mut count = 0;
+    for pos in regions.stored_chunks()? {
+        let data = regions.load(&pos)?.context("Stored chunk vanished")?;
+        // Palette data never has this length, so these chunks were converted by an interrupted migration
+        if data.len() != CHUNK_VOLUME {
+            continue;
+        }
+        // A palette of all 256 old ids with 8 bits per index, so the indices are the old bytes.
+        // The palette is compacted the next time the chunk is saved.
+        let mut converted = Vec::with_capacity(2 + 256 * 2 + 1 + CHUNK_VOLUME);
+        converted.extend_from_slice(&256u16.to_le_bytes());
+        for id in 0..256u16 {
+            converted.extend_from_slice(&id.to_le_bytes());
+        }
+        converted.push(8);
+        converted.extend_from_slice(&data);
+        regions.save(&pos, &converted)?;
+        count += 1;
+    }
+    regions.flush()?;
+
+    eprintln!("Server: converted {count} chunks to the palette format");
+    Ok(())
+}
+
+/// Version 2 -> 3: Adds the block state to every palette entry, all blocks get the default state
+fn migrate_block_states(world_directory: &Path) -> anyhow::Result<()> {
+    let mut regions = RegionStorage::new(world_directory)?;
+
+    let mut count = 0;
+    for pos in regions.stored_chunks()? {
+        let data = regions.load(&pos)?.context("Stored chunk vanished")?;
+        // Chunks converted by an interrupted migration do not have the version 2 layout
+        let Some(converted) = add_block_states(&data) else {
+            continue;
+        };
+        PalettedBlocks::from_bytes(&converted)
+            .with_context(|| format!("Chunk {pos:?} is corrupt"))?;
+        regions.save(&pos, &converted)?;
+        count += 1;
+    }
+    regions.flush()?;
+
+    eprintln!("Server: added block states to {count} chunks");
+    Ok(())
+}
+
+/// Inserts a zero state byte after every palette entry of version 2 chunk data.
+/// Returns None if the data does not have the version 2 layout.
+fn add_block_states(data: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::from_le_bytes(*data.first_chunk::<2>()?) as usize;
+    let palette_end = 2 + len * 2;
+    let bits = *data.get(palette_end)? as usize;
+    if data.len() != palette_end + 1 + (CHUNK_VOLUME * bits).div_ceil(64) * 8 {
+        return None;
+    }
+
+    let mut converted = data[..2].to_vec();
+    for id in data[2..palette_end].chunks_exact(2) {
+        converted.extend_from_slice(id);
+        converted.push(0);
+    }
+    converted.extend_from_slice(&data[palette_end..]);
+    Some(converted)
+}
+
+/// The run-length encoding is only used if it is smaller than the raw blocks
+fn create_chunk_package(chunk: &ChunkData, pos: &[i32; 3], encoding: ChunkEncoding) -> Arc<[u8]> {
+    let blocks = chunk.blocks.to_vec();
+    let encoding = if run_length_size(&blocks) < blocks.len() * Block::SIZE {
+        encoding
+    } else {
+        ChunkEncoding::Raw
+    };
+    ClientBound::Chunk {
+        pos: *pos,
+        blocks,
+        encoding,
+    }
+    .to_arc()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_area_walks_the_box_in_order() {
+        let (from, to) = ([-1, 2, 0], [1, 3, 2]);
+        let mut expected = Vec::new();
+        for x in from[0]..=to[0] {
+            for z in from[2]..=to[2] {
+                for y in from[1]..=to[1] {
+                    expected.push([x, y, z]);
+                }
+            }
+        }
+        let area = ChunkArea::new(from, to);
+        assert_eq!(area.size(), expected.len() as u64);
+        assert_eq!(area.collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn huge_chunk_area_is_not_stored() {
+        let mut area = ChunkArea::new([i32::MIN; 3], [i32::MAX; 3]);
+        assert_eq!(area.size(), u64::MAX);
+        assert_eq!(area.next(), Some([i32::MIN; 3]));
+        assert_eq!(area.next(), Some([i32::MIN, i32::MIN + 1, i32::MIN]));
+        assert_eq!(ChunkArea::new([0; 3], [0, -1, 0]).count(), 0);
+    }
 }
@@ -1 +1,512 @@
+//! Procedural generation of the chunks from the world seed
 
+use noise::{BasicMulti, NoiseFn, Perlin, Seedable};
+
+use crate::game::{BlockType, ChunkIndex};
+
+use super::biome::Biome;
+use super::features::{ChunkWriter, Feature, Tree};
+use super::world::ChunkData;
+
+const CHUNK_SIZE: usize = crate::game::CHUNK_SIZE as usize;
+
+// Block ids as defined in config/blocks.json
+pub const AIR: BlockType = 0;
+pub const GRASS: BlockType = 1;
+pub const DIRT: BlockType = 2;
+pub const STONE: BlockType = 3;
+pub const LEAVES: BlockType = 4;
+pub const LOG: BlockType = 5;
+pub const SAND: BlockType = 6;
+pub const SNOW: BlockType = 7;
+
+/// Height of the terrain for a noise value of 1 and an amplitude of 1
+const HEIGHT_SCALE: f64 = 32.0;
+/// Horizontal size of the climate features, larger values give larger biomes
+const CLIMATE_SCALE: f64 = 400.0;
+/// In density mode the 3D noise can move the surface up or down by this many blocks
+const OVERHANG_DEPTH: f64 = 12.0;
+/// Size of the cave systems
+const CAVE_SCALE: f64 = 60.0;
+/// Larger values give wider and more caves
+const CAVE_WIDTH: f64 = 0.08;
+/// Number of blocks below the surface block which are filler, below that is stone
+const FILLER_DEPTH: usize = 4;
+
+/// Creates the content of chunks which were never modified.
+/// Generation has to be deterministic, unmodified chunks are not stored on disk.
+/// Changing the output of a generator leaves seams in existing worlds, so worlds keep the
+/// generator they were created with, see [`LegacyGenerator`].
+pub trait WorldGenerator: Send + Sync {
+    fn generate(&self, pos: &ChunkIndex) -> ChunkData;
+
+    /// Biome of the block column x, z
+    fn biome(&self, _x: i32, _z: i32) -> Biome {
+        Biome::Plains
+    }
+}
+
+/// Which generator a world uses, configured in the `generator` field of settings.json
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GeneratorSettings {
+    /// Noise based terrain with biomes, ores and trees
+    #[default]
+    Terrain,
+    /// Horizontal layers of blocks
+    Flat {
+        /// From bottom to top, the top of the last layer is at y = 0
+        #[serde(default = "default_flat_layers")]
+        layers: Vec<FlatLayerSettings>,
+    },
+    /// No blocks at all
+    Void,
+    /// The terrain of the first release, used by the worlds created with it
+    Legacy,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FlatLayerSettings {
+    /// Name of the block in config/blocks.json
+    pub block: String,
+    /// Thickness of the layer in blocks
+    pub height: u32,
+}
+
+fn default_flat_layers() -> Vec<FlatLayerSettings> {
+    vec![
+        FlatLayerSettings {
+            block: "stone".into(),
+            height: 3,
+        },
+        FlatLayerSettings {
+            block: "dirt".into(),
+            height: 2,
+        },
+        FlatLayerSettings {
+            block: "grass".into(),
+            height: 1,
+        },
+    ]
+}
+
+/// How the shape of the terrain is computed, configured in settings.json
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerrainMode {
+    /// Every column is filled up to the height of a 2D noise
+    #[default]
+    Heightmap,
+    /// A 3D noise around the height gives overhangs and caves are carved out of the terrain
+    Density,
+}
+
+/// How one kind of ore is distributed in the stone, configured in settings.json
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OreSettings {
+    /// Name of the ore block in config/blocks.json
+    pub block: String,
+    /// Lowest block height of the ore
+    pub min_y: i32,
+    /// Highest block height of the ore
+    pub max_y: i32,
+    /// Number of steps of the random walk which forms a vein
+    pub vein_size: u32,
+    /// Average number of veins per chunk in the height range
+    pub frequency: f64,
+}
+
+pub fn default_ores() -> Vec<OreSettings> {
+    vec![
+        OreSettings {
+            block: "iron_ore".into(),
+            min_y: -64,
+            max_y: 16,
+            vein_size: 8,
+            frequency: 6.0,
+        },
+        OreSettings {
+            block: "gold_ore".into(),
+            min_y: -64,
+            max_y: -16,
+            vein_size: 6,
+            frequency: 2.0,
+        },
+        OreSettings {
+            block: "diamond_ore".into(),
+            min_y: -64,
+            max_y: -40,
+            vein_size: 4,
+            frequency: 0.5,
+        },
+    ]
+}
+
+/// An ore with its block id resolved
+pub struct Ore {
+    pub block: BlockType,
+    pub settings: OreSettings,
+}
+
+/// Deterministic random number for a position, different `salt`s give independent numbers
+pub fn position_random(seed: u32, pos: [i32; 3], salt: u32) -> u64 {
+    let mut hash = ((salt as u64) << 32) | seed as u64;
+    for x in pos {
+        hash = splitmix64(hash ^ x as u32 as u64);
+    }
+    hash
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Maps a random number uniformly to [0, 1)
+pub fn unit_float(random: u64) -> f64 {
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub struct TerrainGenerator {
+    seed: u32,
+    mode: TerrainMode,
+    height: BasicMulti<Perlin>,
+    temperature: Perlin,
+    humidity: Perlin,
+    overhang: Perlin,
+    caves: [Perlin; 2],
+    features: Vec<Box<dyn Feature>>,
+    ores: Vec<Ore>,
+}
+
+impl TerrainGenerator {
+    pub fn new(seed: u32, mode: TerrainMode, ores: Vec<Ore>) -> Self {
+        let mut height = BasicMulti::<Perlin>::default();
+        height.octaves = 5;
+        height.persistence = 0.25;
+        height = height.set_seed(seed);
+
+        TerrainGenerator {
+            seed,
+            mode,
+            height,
+            temperature: Perlin::new(seed.wrapping_add(1)),
+            humidity: Perlin::new(seed.wrapping_add(2)),
+            overhang: Perlin::new(seed.wrapping_add(3)),
+            caves: [
+                Perlin::new(seed.wrapping_add(4)),
+                Perlin::new(seed.wrapping_add(5)),
+            ],
+            features: vec![Box::new(Tree)],
+            ores,
+        }
+    }
+
+    /// Temperature and humidity of a column, both roughly in [-1, 1]
+    fn climate(&self, x: f64, z: f64) -> (f64, f64) {
+        let point = [x / CLIMATE_SCALE, z / CLIMATE_SCALE];
+        (self.temperature.get(point), self.humidity.get(point))
+    }
+
+    /// Height of the terrain and the biome at the center of the block column x, z
+    pub fn column(&self, x: i32, z: i32) -> (f64, Biome) {
+        let (x, z) = (x as f64 + 0.5, z as f64 + 0.5);
+        let (temperature, humidity) = self.climate(x, z);
+
+        let weights = Biome::weights(temperature, humidity);
+        let total: f64 = weights.iter().sum();
+        let (amplitude, offset) = Biome::ALL.iter().zip(weights).fold(
+            (0.0, 0.0),
+            |(amplitude, offset), (biome, weight)| {
+                (
+                    amplitude + biome.height_amplitude() * weight / total,
+                    offset + biome.height_offset() * weight / total,
+                )
+            },
+        );
+
+        let height = self.height.get([x / 100.0, z / 100.0]) * HEIGHT_SCALE * amplitude + offset;
+        (height, Biome::from_climate(temperature, humidity))
+    }
+
+    /// Whether the terrain is solid at a block in density mode, without caves.
+    /// `height` is the height of the column.
+    fn dense(&self, pos: [i32; 3], height: f64) -> bool {
+        let [x, y, z] = pos.map(|x| x as f64 + 0.5);
+        let base = (height - y) / OVERHANG_DEPTH;
+        // The noise can not change the result far away from the surface
+        if base.abs() > 1.0 {
+            return base > 0.0;
+        }
+        base + self.overhang.get([x / 40.0, y / 30.0, z / 40.0]) > 0.0
+    }
+
+    /// Whether a cave is carved out at this block in density mode
+    fn cave(&self, pos: [i32; 3]) -> bool {
+        let [x, y, z] = pos.map(|x| x as f64 + 0.5);
+        let point = [x / CAVE_SCALE, y / (CAVE_SCALE * 0.6), z / CAVE_SCALE];
+        self.caves
+            .iter()
+            .all(|noise| noise.get(point).abs() < CAVE_WIDTH)
+    }
+
+    /// Whether the generated terrain has a block at this position, ignoring ores and features
+    pub fn is_solid(&self, pos: [i32; 3]) -> bool {
+        let (height, _) = self.column(pos[0], pos[2]);
+        match self.mode {
+            TerrainMode::Heightmap => pos[1] as f64 + 0.5 <= height,
+            TerrainMode::Density => self.dense(pos, height) && !self.cave(pos),
+        }
+    }
+
+    /// Replaces stone with ore veins. Veins do not cross chunk borders.
+    fn place_ores(&self, pos: &ChunkIndex, chunk: &mut ChunkData) {
+        let chunk_y = pos[1] * CHUNK_SIZE as i32;
+
+        for (salt, ore) in self.ores.iter().enumerate() {
+            let settings = &ore.settings;
+            if settings.max_y < chunk_y || settings.min_y >= chunk_y + CHUNK_SIZE as i32 {
+                continue;
+            }
+
+            let mut random = position_random(self.seed, *pos, 1000 + salt as u32);
+            let mut next_random = || {
+                random = splitmix64(random);
+                random
+            };
+
+            // The fractional part of the frequency is the chance for one additional vein
+            let mut veins = settings.frequency.floor() as u32;
+            if unit_float(next_random()) < settings.frequency.fract() {
+                veins += 1;
+            }
+
+            for _ in 0..veins {
+                let mut block = [0; 3].map(|_| (next_random() % CHUNK_SIZE as u64) as i32);
+                for _ in 0..settings.vein_size {
+                    let y = chunk_y + block[1];
+                    if (0..CHUNK_SIZE as i32).contains(&block[0])
+                        && (0..CHUNK_SIZE as i32).contains(&block[1])
+                        && (0..CHUNK_SIZE as i32).contains(&block[2])
+                        && (settings.min_y..=settings.max_y).contains(&y)
+                    {
+                        let local = block.map(|x| x as usize);
+                        if chunk.get(local.map(|x| x as u8)) == STONE {
+                            chunk.set(local, ore.block);
+                        }
+                    }
+                    // Walk one block in a random direction
+                    let direction = next_random();
+                    let axis = (direction % 3) as usize;
+                    block[axis] += if direction & 8 == 0 { 1 } else { -1 };
+                }
+            }
+        }
+    }
+
+    /// Places all features whose blocks can reach into this chunk
+    fn place_features(&self, pos: &ChunkIndex, chunk: &mut ChunkData) {
+        let mut writer = ChunkWriter::new(chunk, pos);
+        let y_range = writer.y_range();
+        let [x, _, z] = pos.map(|x| x * CHUNK_SIZE as i32);
+
+        for (salt, feature) in self.features.iter().enumerate() {
+            let extent = feature.horizontal_extent();
+            let max_density = Biome::ALL
+                .map(|biome| feature.density(biome))
+                .into_iter()
+                .fold(0.0, f64::max);
+
+            for origin_x in x - extent..x + CHUNK_SIZE as i32 + extent {
+                for origin_z in z - extent..z + CHUNK_SIZE as i32 + extent {
+                    let random = position_random(self.seed, [origin_x, 0, origin_z], salt as u32);
+                    let chance = unit_float(random);
+                    // Most columns are skipped before the expensive terrain lookup
+                    if chance >= max_density {
+                        continue;
+                    }
+                    let (height, biome) = self.column(origin_x, origin_z);
+                    if chance >= feature.density(biome)
+                        || !feature.allowed_on(biome.surface_block(height.floor() as i32))
+                    {
+                        continue;
+                    }
+                    // The feature starts on top of the highest terrain block
+                    let origin_y = (height - 0.5).floor() as i32 + 1;
+                    if origin_y + feature.height() <= y_range.start || origin_y >= y_range.end {
+                        continue;
+                    }
+                    // Overhangs and caves can move the surface away from the height
+                    if self.mode == TerrainMode::Density
+                        && (!self.is_solid([origin_x, origin_y - 1, origin_z])
+                            || self.is_solid([origin_x, origin_y, origin_z]))
+                    {
+                        continue;
+                    }
+                    feature.place(
+                        [origin_x, origin_y, origin_z],
+                        splitmix64(random),
+                        &mut writer,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl WorldGenerator for TerrainGenerator {
+    fn generate(&self, pos: &ChunkIndex) -> ChunkData {
+        let mut chunk = ChunkData::empty();
+
+        let [x, y, z] = pos;
+
+        for xx in 0..CHUNK_SIZE {
+            for zz in 0..CHUNK_SIZE {
+                let world_x = x * CHUNK_SIZE as i32 + xx as i32;
+                let world_z = z * CHUNK_SIZE as i32 + zz as i32;
+                let (height, biome) = self.column(world_x, world_z);
+                let surface = biome.surface_block(height.floor() as i32);
+                let filler = biome.filler_block();
+
+                if self.mode == TerrainMode::Density {
+                    // Count the solid blocks above, starting above the chunk to know the depth at its top
+                    let mut depth = 0;
+                    for yy in (0..CHUNK_SIZE + FILLER_DEPTH + 1).rev() {
+                        let block_pos = [world_x, y * CHUNK_SIZE as i32 + yy as i32, world_z];
+                        if !self.dense(block_pos, height) {
+                            depth = 0;
+                            continue;
+                        }
+                        if yy < CHUNK_SIZE && !self.cave(block_pos) {
+                            let block = match depth {
+                                0 => surface,
+                                d if d <= FILLER_DEPTH => filler,
+                                _ => STONE,
+                            };
+                            chunk.set([xx, yy, zz], block);
+                        }
+                        depth += 1;
+                    }
+                    continue;
+                }
+
+                for yy in 0..CHUNK_SIZE {
+                    let y = (y * CHUNK_SIZE as i32 + yy as i32) as f64 + 0.5;
+                    if y <= height - 1.0 - FILLER_DEPTH as f64 {
+                        chunk.set([xx, yy, zz], STONE);
+                    } else if y <= height - 1.0 {
+                        // Some filler below the surface
+                        chunk.set([xx, yy, zz], filler);
+                    } else if y <= height {
+                        chunk.set([xx, yy, zz], surface);
+                    }
+                }
+            }
+        }
+
+        self.place_ores(pos, &mut chunk);
+        self.place_features(pos, &mut chunk);
+
+        chunk
+    }
+
+    fn biome(&self, x: i32, z: i32) -> Biome {
+        let (temperature, humidity) = self.climate(x as f64 + 0.5, z as f64 + 0.5);
+        Biome::from_climate(temperature, humidity)
+    }
+}
+
+/// Layers of blocks, the same in every column
+pub struct FlatGenerator {
+    /// Block of every height, starting at `bottom`
+    blocks: Vec<BlockType>,
+    bottom: i32,
+}
+
+impl FlatGenerator {
+    /// `layers` are the block and thickness of each layer from bottom to top
+    pub fn new(layers: &[(BlockType, u32)]) -> Self {
+        let blocks = layers
+            .iter()
+            .flat_map(|(block, height)| std::iter::repeat_n(*block, *height as usize))
+            .collect::<Vec<_>>();
+        FlatGenerator {
+            bottom: -(blocks.len() as i32),
+            blocks,
+        }
+    }
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn generate(&self, pos: &ChunkIndex) -> ChunkData {
+        let mut chunk = ChunkData::empty();
+        for yy in 0..CHUNK_SIZE {
+            let y = pos[1] * CHUNK_SIZE as i32 + yy as i32 - self.bottom;
+            let Some(&block) = usize::try_from(y).ok().and_then(|y| self.blocks.get(y)) else {
+                continue;
+            };
+            for xx in 0..CHUNK_SIZE {
+                for zz in 0..CHUNK_SIZE {
+                    chunk.set([xx, yy, zz], block);
+                }
+            }
+        }
+        chunk
+    }
+}
+
+/// A world without any blocks
+pub struct VoidGenerator;
+
+impl WorldGenerator for VoidGenerator {
+    fn generate(&self, _pos: &ChunkIndex) -> ChunkData {
+        ChunkData::empty()
+    }
+}
+
+/// Height of the first terrain for a noise value of 1, it must not change
+const LEGACY_HEIGHT_SCALE: f64 = 32.0;
+
+/// The heightmap terrain of the first release, grass on dirt on stone without biomes, ores and
+/// trees. Worlds from before the generator was stored in settings.json use it, so the chunks
+/// generated now fit to the chunks stored back then.
+pub struct LegacyGenerator {
+    height: BasicMulti<Perlin>,
+}
+
+impl LegacyGenerator {
+    pub fn new(seed: u32) -> Self {
+        let mut height = BasicMulti::<Perlin>::default();
+        height.octaves = 5;
+        height.persistence = 0.25;
+        LegacyGenerator {
+            height: height.set_seed(seed),
+        }
+    }
+}
+
+impl WorldGenerator for LegacyGenerator {
+    fn generate(&self, pos: &ChunkIndex) -> ChunkData {
+        let mut chunk = ChunkData::empty();
+        let [x, y, z] = pos.map(|x| x * CHUNK_SIZE as i32);
+        for xx in 0..CHUNK_SIZE {
+            for zz in 0..CHUNK_SIZE {
+                let column = [x + xx as i32, z + zz as i32].map(|x| x as f64 + 0.5);
+                let height = self.height.get(column.map(|x| x / 100.0)) * LEGACY_HEIGHT_SCALE;
+                for yy in 0..CHUNK_SIZE {
+                    let y = (y + yy as i32) as f64 + 0.5;
+                    if y <= height - 5.0 {
+                        chunk.set([xx, yy, zz], STONE);
+                    } else if y <= height - 1.0 {
+                        chunk.set([xx, yy, zz], DIRT);
+                    } else if y <= height {
+                        chunk.set([xx, yy, zz], GRASS);
+                    }
+                }
+            }
+        }
+        chunk
+    }
+}